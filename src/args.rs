@@ -1,44 +1,450 @@
-use clap::{arg, Command};
+use std::path::PathBuf;
+
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command};
+
+use crate::commands::Layer;
+use crate::config::Config;
+use crate::connection::AuthLevel;
+use crate::device::Selector;
+use crate::endpoint::{self, ConnectionConfig, Endpoint};
 
 impl From<crate::commands::Commands> for Command {
   fn from(msgs: crate::commands::Commands) -> Self {
     use crate::commands::Commands;
     match msgs {
-      Commands::LayerSet(_) => Command::new("LayerSet")
-        .about("Sets the current layer on the keyboard")
-        .arg(arg!([LAYER] "The layer to set").required(true)),
+      Commands::LayerSet(_) => {
+        Command::new("LayerSet").about("Sets the current layer on the keyboard").arg(
+          arg!([LAYER] "The layer number or name to set")
+            .required(true)
+            .value_parser(value_parser!(Layer)),
+        )
+      }
+      Commands::LayerNext(_) => Command::new("LayerNext")
+        .about("Moves to the next layer, wrapping around")
+        .arg(layer_count_arg()),
+      Commands::LayerPrev(_) => Command::new("LayerPrev")
+        .about("Moves to the previous layer, wrapping around")
+        .arg(layer_count_arg()),
+      Commands::LayerToggle(_, _) => Command::new("LayerToggle")
+        .about("Toggles between two layers")
+        .arg(arg!(<A> "The layer to set when B is active").value_parser(value_parser!(Layer)))
+        .arg(arg!(<B> "The layer to set when A is active").value_parser(value_parser!(Layer))),
+      Commands::LayerFor(_, _) => Command::new("LayerFor")
+        .about("Sets a layer for a number of seconds, then reverts to the previous layer")
+        .arg(arg!(<LAYER> "The layer number or name to set").value_parser(value_parser!(Layer)))
+        .arg(arg!(<SECONDS> "How long to keep the layer").value_parser(value_parser!(u64))),
+      Commands::Cli(_, _) => Command::new("Cli")
+        .about("Sends a command to the keyboard firmware CLI and prints its output")
+        .alias("cli")
+        .arg(arg!(<COMMAND> "The CLI command to run"))
+        .arg(
+          arg!(-t --timeout <MS> "Milliseconds to wait for the command to complete")
+            .default_value("5000")
+            .value_parser(value_parser!(u64)),
+        ),
+      Commands::SleepMode => Command::new("SleepMode").about("Puts the keyboard into sleep mode"),
+      Commands::FlashMode => {
+        Command::new("FlashMode").about("Reboots the keyboard into its bootloader for flashing")
+      }
+      Commands::ManufacturingTest(_, _) => Command::new("ManufacturingTest")
+        .about("Runs a manufacturing test on the keyboard")
+        .arg(arg!([CMD] "The test command").required(true).value_parser(value_parser!(u16)))
+        .arg(arg!([ARG] "The test argument").default_value("0").value_parser(value_parser!(u16))),
+      Commands::Info => Command::new("Info").about("Shows device and firmware information"),
+    }
+  }
+}
+
+fn layer_count_arg() -> clap::Arg {
+  arg!(-c --count <N> "Number of layers to wrap around, overrides the config")
+    .value_parser(value_parser!(u8))
+}
+
+impl TryFrom<(&str, &ArgMatches)> for crate::commands::Commands {
+  type Error = String;
+
+  fn try_from((name, matches): (&str, &ArgMatches)) -> Result<Self, Self::Error> {
+    use crate::commands::Commands;
+    match name {
+      "LayerSet" => Ok(Commands::LayerSet(matches.get_one::<Layer>("LAYER").unwrap().clone())),
+      "LayerNext" => Ok(Commands::LayerNext(matches.get_one::<u8>("count").copied())),
+      "LayerPrev" => Ok(Commands::LayerPrev(matches.get_one::<u8>("count").copied())),
+      "LayerToggle" => Ok(Commands::LayerToggle(
+        matches.get_one::<Layer>("A").unwrap().clone(),
+        matches.get_one::<Layer>("B").unwrap().clone(),
+      )),
+      "LayerFor" => Ok(Commands::LayerFor(
+        matches.get_one::<Layer>("LAYER").unwrap().clone(),
+        *matches.get_one::<u64>("SECONDS").unwrap(),
+      )),
+      "Cli" => Ok(Commands::Cli(
+        matches.get_one::<String>("COMMAND").unwrap().clone(),
+        *matches.get_one::<u64>("timeout").unwrap(),
+      )),
+      "SleepMode" => Ok(Commands::SleepMode),
+      "FlashMode" => Ok(Commands::FlashMode),
+      "ManufacturingTest" => Ok(Commands::ManufacturingTest(
+        *matches.get_one::<u16>("CMD").unwrap(),
+        *matches.get_one::<u16>("ARG").unwrap(),
+      )),
+      "Info" => Ok(Commands::Info),
+      _ => Err(format!("Command does not exist: {}", name)),
     }
   }
 }
 
+/// Arguments describing an event to wait for, shared by `wait` script lines and `wait-for`
+fn condition_args(command: Command) -> Command {
+  command
+    .arg(arg!(<EVENT> "The event to wait for").value_parser([
+      "layer",
+      "host-macro",
+      "volume",
+      "cli",
+      "connected",
+    ]))
+    .arg(arg!([VALUE] "Layer, host macro index, volume command or CLI output text to match"))
+    .arg(
+      arg!(-t --timeout <MS> "Milliseconds to wait before failing")
+        .default_value("10000")
+        .value_parser(value_parser!(u64)),
+    )
+}
+
+pub fn condition(matches: &ArgMatches) -> Result<crate::event::Condition, String> {
+  use crate::event::Condition;
+  let value = matches.get_one::<String>("VALUE").cloned();
+  let number = |name: &str| -> Result<Option<u16>, String> {
+    value.as_ref().map(|v| v.parse().map_err(|_| format!("Invalid {}: {}", name, v))).transpose()
+  };
+  match matches.get_one::<String>("EVENT").unwrap().as_str() {
+    "layer" => match value.as_ref().map(|v| v.parse::<u8>()) {
+      Some(Ok(layer)) => Ok(Condition::Layer(layer)),
+      _ => Err("wait layer requires a layer number".to_string()),
+    },
+    "host-macro" => Ok(Condition::HostMacro(number("host macro index")?)),
+    "volume" => Ok(Condition::Volume(value)),
+    "cli" => Ok(Condition::Cli(value)),
+    "connected" => Ok(Condition::Connected),
+    _ => unreachable!(),
+  }
+}
+
+/// Keyboard selection and configuration arguments shared by every subcommand that talks to a
+/// keyboard
+///
+/// `--serial` and `--name` are shorthands for the `serial:` and `name:` selector terms. With
+/// `multiple` each may be repeated and a keyboard matching any of them is selected.
+fn keyboard_args(command: Command, multiple: bool) -> Command {
+  let (keyboard, serial, name) = (
+    arg!(-k --keyboard <SELECTOR> "Keyboard selector, e.g. auto, name:Ergo*,type:usb or index:1")
+      .value_parser(|s: &str| s.parse::<Selector>()),
+    arg!(-s --serial <SERIAL> "The serial number of the keyboard"),
+    arg!(-n --name <NAME> "The name of the keyboard, may be a glob pattern"),
+  );
+  let command = command.arg(config_arg());
+  if multiple {
+    command
+      .arg(keyboard.action(ArgAction::Append))
+      .arg(serial.action(ArgAction::Append))
+      .arg(name.action(ArgAction::Append))
+  } else {
+    command.arg(keyboard.conflicts_with_all(["serial", "name"])).arg(serial).arg(name)
+  }
+}
+
+fn config_arg() -> clap::Arg {
+  arg!(-c --config <CONFIG> "Path to the configuration file").env("HIDIOKB_CONFIG")
+}
+
+/// Options choosing how to reach hid-io-core or which config to use
+const CONNECTION_ARGS: &[&str] = &[
+  "host",
+  "port",
+  "ca",
+  "pin",
+  "insecure",
+  "key",
+  "connect-timeout",
+  "client-name",
+  "auth",
+  "retry-delay",
+  "max-retries",
+  "config",
+];
+
+/// Whether the command line sets one of the connection or config options
+///
+/// A running instance would ignore them, so such a command runs over its own connection.
+pub fn overrides_connection(matches: &ArgMatches) -> bool {
+  let given = |m: &ArgMatches| {
+    CONNECTION_ARGS.iter().any(|id| {
+      m.try_contains_id(id).unwrap_or(false)
+        && m.value_source(id) == Some(clap::parser::ValueSource::CommandLine)
+    })
+  };
+  given(matches) || matches.subcommand().is_some_and(|(_, sub)| given(sub))
+}
+
+/// Arguments shared by `subscribe` and `daemon`
+fn subscribe_args(command: Command) -> Command {
+  keyboard_args(command, true).arg(arg!(-a --all "Subscribe to every attached keyboard"))
+}
+
+/// The keyboard selected by a single-keyboard subcommand, `auto` when none was given
+pub fn selector(matches: &ArgMatches, config: &Config) -> Result<Selector, String> {
+  let selector = match matches.get_one::<Selector>("keyboard") {
+    Some(selector) => selector.clone(),
+    None => match (matches.get_one::<String>("serial"), matches.get_one::<String>("name")) {
+      (Some(serial), Some(name)) => Selector::serial(serial).and(Selector::name(name)),
+      (Some(serial), None) => Selector::serial(serial),
+      (None, Some(name)) => Selector::name(name),
+      (None, None) => Selector::auto(),
+    },
+  };
+  selector.resolve_aliases(config)
+}
+
+/// Every selector given to a multi-keyboard subcommand
+pub fn selectors(matches: &ArgMatches, config: &Config) -> Result<Vec<Selector>, String> {
+  let keyboards = matches.get_many::<Selector>("keyboard").unwrap_or_default().cloned();
+  let serials =
+    matches.get_many::<String>("serial").unwrap_or_default().map(|s| Selector::serial(s));
+  let names = matches.get_many::<String>("name").unwrap_or_default().map(|s| Selector::name(s));
+  keyboards.chain(serials).chain(names).map(|s| s.resolve_aliases(config)).collect()
+}
+
+pub fn retry_policy(matches: &ArgMatches) -> crate::connection::RetryPolicy {
+  let delay = *matches.get_one::<u64>("retry-delay").unwrap();
+  crate::connection::RetryPolicy {
+    initial_delay: std::time::Duration::from_millis(delay),
+    max_retries: matches.get_one::<u32>("max-retries").copied(),
+  }
+}
+
+/// Authentication level given with `--auth`, or the lowest the subcommand needs
+pub fn auth_level(matches: &ArgMatches, config: &Config) -> AuthLevel {
+  match matches.get_one::<String>("auth").map(String::as_str) {
+    Some("basic") => AuthLevel::Basic,
+    Some(_) => AuthLevel::Privileged,
+    None => AuthLevel::required(matches.subcommand_name().unwrap_or_default(), config),
+  }
+}
+
+/// Where to reach hid-io-core, from the command line, then the config, then the defaults
+pub fn endpoint(matches: &ArgMatches, config: &ConnectionConfig) -> Endpoint {
+  let timeout = matches.get_one::<u64>("connect-timeout").copied().or(config.timeout);
+  Endpoint {
+    host: matches
+      .get_one::<String>("host")
+      .or(config.host.as_ref())
+      .cloned()
+      .unwrap_or_else(|| endpoint::DEFAULT_HOST.to_string()),
+    port: matches.get_one::<u16>("port").copied().or(config.port).unwrap_or(endpoint::DEFAULT_PORT),
+    ca: matches.get_one::<PathBuf>("ca").or(config.ca.as_ref()).cloned(),
+    pin: matches.get_one::<String>("pin").or(config.pin.as_ref()).cloned(),
+    insecure: matches.get_flag("insecure") || config.insecure,
+    key: matches.get_one::<PathBuf>("key").or(config.key.as_ref()).cloned(),
+    timeout: std::time::Duration::from_millis(timeout.unwrap_or(5000)),
+    name: matches
+      .get_one::<String>("client-name")
+      .or(config.name.as_ref())
+      .cloned()
+      .unwrap_or_else(|| endpoint::DEFAULT_NAME.to_string()),
+  }
+}
+
 pub fn cli() -> Command {
   Command::new("hidiokb")
     .about("Hidio Keyboard CLI")
     .subcommand_required(true)
     .arg_required_else_help(true)
     .allow_external_subcommands(true)
+    .arg(
+      arg!(--"retry-delay" <MS> "Milliseconds before the first reconnect, doubled on each retry")
+        .global(true)
+        .env("HIDIOKB_RETRY_DELAY")
+        .default_value("500")
+        .value_parser(value_parser!(u64)),
+    )
+    .arg(
+      arg!(--"max-retries" <N> "Give up connecting to hid-io-core after this many retries")
+        .global(true)
+        .env("HIDIOKB_MAX_RETRIES")
+        .value_parser(value_parser!(u32)),
+    )
+    .arg(
+      arg!(--host <HOST> "Host hid-io-core listens on [default: localhost]")
+        .global(true)
+        .env("HIDIOKB_HOST"),
+    )
+    .arg(
+      arg!(--port <PORT> "Port hid-io-core listens on [default: 7185]")
+        .global(true)
+        .env("HIDIOKB_PORT")
+        .value_parser(value_parser!(u16)),
+    )
+    .arg(
+      arg!(--ca <PEM> "Trust only hid-io-core certificates signed by these authorities")
+        .global(true)
+        .env("HIDIOKB_CA")
+        .value_parser(value_parser!(PathBuf))
+        .conflicts_with("pin"),
+    )
+    .arg(
+      arg!(--pin <SHA256> "Trust only the hid-io-core certificate with this fingerprint")
+        .global(true)
+        .env("HIDIOKB_PIN"),
+    )
+    .arg(
+      arg!(--insecure "Trust any certificate from a remote hid-io-core, as is done on loopback")
+        .global(true)
+        .env("HIDIOKB_INSECURE")
+        .conflicts_with_all(["ca", "pin"]),
+    )
+    .arg(
+      arg!(--key <FILE> "Copy of the hid-io-core key file, for a hid-io-core on another machine")
+        .global(true)
+        .env("HIDIOKB_KEY")
+        .value_parser(value_parser!(PathBuf)),
+    )
+    .arg(
+      arg!(--"connect-timeout" <MS> "Milliseconds to wait for hid-io-core [default: 5000]")
+        .global(true)
+        .env("HIDIOKB_CONNECT_TIMEOUT")
+        .value_parser(value_parser!(u64)),
+    )
+    .arg(
+      arg!(--"client-name" <NAME> "Name to register with hid-io-core")
+        .global(true)
+        .env("HIDIOKB_CLIENT_NAME"),
+    )
+    .arg(
+      arg!(--auth <LEVEL> "Authentication level, defaults to the lowest the command needs")
+        .global(true)
+        .env("HIDIOKB_AUTH")
+        .value_parser(["basic", "privileged"]),
+    )
+    .arg(
+      arg!(--"no-daemon" "Connect to hid-io-core directly, even when an instance is running")
+        .global(true),
+    )
+    .subcommand(
+      subscribe_args(Command::new("subscribe"))
+        .about("Subscribes to one or more keyboards")
+        .alias("sub"),
+    )
+    .subcommand(subscribe_args(Command::new("daemon")).about(
+      "Subscribes like subscribe, reporting readiness and status to systemd and logging to the \
+       journal",
+    ))
     .subcommand(
-      Command::new("subscribe")
-        .about("Subscribes to a keyboard")
-        .alias("sub")
+      Command::new("config")
+        .about("Inspects the configuration file")
+        .subcommand_required(true)
+        .subcommand(
+          Command::new("check")
+            .about("Validates the configuration file, reporting each problem by line or key")
+            .arg(config_arg()),
+        ),
+    )
+    .subcommand(
+      Command::new("status")
+        .about("Shows the keyboards and layers of the running subscribe or daemon instance"),
+    )
+    .subcommand(
+      Command::new("install-service")
+        .about("Writes a systemd user unit running the daemon subcommand")
+        .arg(arg!(--unit <NAME> "Name of the unit").default_value("hidiokb"))
         .arg(
-          arg!(-s --serial <SERIAL> "The serial number of the keyboard")
-            .required_unless_present("name"),
+          arg!(--watchdog <SECS> "Restart after this many seconds without hid-io-core, 0 for never")
+            .default_value("30")
+            .value_parser(value_parser!(u64)),
         )
-        .arg(arg!(-n --name <NAME> "The name of the keyboard").required_unless_present("serial"))
-        .arg_required_else_help(true),
+        .arg(arg!(-f --force "Replace an existing unit file"))
+        .arg(arg!(--enable "Reload systemd, then enable and start the unit"))
+        .arg(
+          arg!([ARGS] ... "Arguments passed on to the daemon subcommand, after --")
+            .trailing_var_arg(true)
+            .allow_hyphen_values(true),
+        ),
     )
     .subcommand(
-      Command::new("exec")
-        .about("Executes a command on the keyboard")
+      keyboard_args(Command::new("exec"), false)
+        .about(
+          "Executes a command on the keyboard, through the running instance when it has \
+           privileged access",
+        )
         .subcommand_required(true)
+        .subcommands(crate::commands::Commands::all().into_iter().map(Command::from))
+        .arg_required_else_help(true),
+    )
+    .subcommand(
+      keyboard_args(Command::new("shell"), false)
+        .about("Opens an interactive shell for a keyboard"),
+    )
+    .subcommand(
+      keyboard_args(Command::new("run"), false)
+        .about("Runs a script of keyboard commands over one connection")
+        .arg(arg!([SCRIPT] "The script to run, or - for stdin").default_value("-")),
+    )
+    .subcommand(condition_args(
+      keyboard_args(Command::new("wait-for"), true)
+        .about("Waits for a keyboard event, exiting non-zero on timeout"),
+    ))
+    .subcommand(
+      Command::new("type")
+        .about("Types Unicode text on the host through the hid-io-core daemon")
+        .arg(arg!(<TEXT> "The text to type")),
+    )
+    .subcommand(
+      keyboard_args(Command::new("info"), false)
+        .about("Shows details about a keyboard and hid-io-core")
         .arg(
-          arg!(-s --serial <SERIAL> "The serial number of the keyboard")
-            .required_unless_present("name"),
+          arg!(-f --format <FORMAT> "Output format")
+            .value_parser(["human", "json"])
+            .default_value("human"),
+        ),
+    )
+    .subcommand(
+      Command::new("list")
+        .about("List all keyboard nodes")
+        .arg(
+          arg!(-f --format <FORMAT> "Output format")
+            .value_parser(["table", "json", "csv"])
+            .default_value("table"),
         )
-        .arg(arg!(-n --name <NAME> "The name of the keyboard").required_unless_present("serial"))
-        .subcommand(Command::from(crate::commands::Commands::LayerSet(0)))
-        .arg_required_else_help(true),
+        .arg(arg!(-t --type <TYPE> "Only list keyboards of this type").value_parser(["usb", "ble"]))
+        .arg(arg!(-n --name <PATTERN> "Only list keyboards whose name matches a glob pattern")),
+    )
+}
+
+/// Commands accepted on a line of the interactive shell
+pub fn repl() -> Command {
+  Command::new("hidiokb")
+    .multicall(true)
+    .subcommand_required(true)
+    .subcommands(crate::commands::Commands::all().into_iter().map(Command::from))
+    .subcommand(
+      Command::new("watch").about("Prints keyboard events as they arrive").arg(
+        arg!([STATE] "Turn watching on or off, toggles if omitted").value_parser(["on", "off"]),
+      ),
+    )
+    .subcommand(Command::new("quit").about("Leaves the shell").alias("exit"))
+}
+
+/// Commands accepted on a line of a `run` script
+pub fn script() -> Command {
+  Command::new("hidiokb")
+    .multicall(true)
+    .subcommand_required(true)
+    .subcommands(crate::commands::Commands::all().into_iter().map(Command::from))
+    .subcommand(
+      Command::new("delay")
+        .about("Pauses the script")
+        .arg(arg!(<MS> "Milliseconds to pause for").value_parser(value_parser!(u64))),
     )
-    .subcommand(Command::new("list").about("List all keyboard nodes"))
+    .subcommand(condition_args(
+      Command::new("wait").about("Pauses the script until a keyboard event arrives"),
+    ))
 }
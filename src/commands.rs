@@ -1,3 +1,296 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use hid_io_client::capnp_rpc;
+use hid_io_core::{hidio_capnp, keyboard_capnp};
+
+use crate::config::Config;
+use crate::event::Event;
+use crate::keysub::KeyboardSubscriberImpl;
+
+/// Silence after the last CLI output chunk that is taken as the end of the command
+const CLI_IDLE: Duration = Duration::from_millis(500);
+
+/// A layer given by number, or by one of the names in the keyboard's profile
+#[derive(Debug, Clone)]
+pub enum Layer {
+  Number(u8),
+  Name(String),
+}
+
+impl FromStr for Layer {
+  type Err = std::convert::Infallible;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(s.parse().map(Layer::Number).unwrap_or_else(|_| Layer::Name(s.to_string())))
+  }
+}
+
+impl fmt::Display for Layer {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Layer::Number(layer) => write!(f, "{}", layer),
+      Layer::Name(name) => write!(f, "{}", name),
+    }
+  }
+}
+
+/// What a command needs to know about the keyboard beyond its node
+pub struct Context {
+  pub serial: String,
+  /// Number of layers relative layer commands wrap around
+  pub layer_count: Option<u8>,
+  /// Layer names from the keyboard's profile, in layer order
+  pub layer_names: Vec<String>,
+  /// Current layer, `None` when no subscribed session has seen the keyboard report one
+  ///
+  /// Updated by every layer command, so a shell or script sharing the context keeps track.
+  pub layer: Cell<Option<u8>>,
+}
+
+impl Context {
+  pub fn new(serial: String, config: &Config) -> Self {
+    Self {
+      layer_count: config.layer_count(&serial),
+      layer_names: config.profile(&serial).map(|p| p.layer_names.clone()).unwrap_or_default(),
+      layer: Cell::new(crate::modules::layer::load_layer(&serial)),
+      serial,
+    }
+  }
+
+  fn resolve(&self, layer: &Layer) -> Result<u8, capnp::Error> {
+    match layer {
+      Layer::Number(layer) => Ok(*layer),
+      Layer::Name(name) => match self.layer_names.iter().position(|n| n == name) {
+        Some(layer) => Ok(layer as u8),
+        None => Err(capnp::Error::failed(format!(
+          "Unknown layer {} for {}, expected a number or one of: {}",
+          name,
+          self.serial,
+          self.layer_names.join(", ")
+        ))),
+      },
+    }
+  }
+
+  /// Current layer, relative layer commands fail rather than guess one
+  fn layer(&self) -> Result<u8, capnp::Error> {
+    self.layer.get().ok_or_else(|| {
+      capnp::Error::failed(format!(
+        "Current layer of {} unknown, is subscribe running?",
+        self.serial
+      ))
+    })
+  }
+
+  fn layer_count(&self, count: Option<u8>) -> Result<u8, capnp::Error> {
+    match count.or(self.layer_count) {
+      Some(0) | None => Err(capnp::Error::failed(
+        "Layer count not configured, pass --count or set `layers` in the config".to_string(),
+      )),
+      Some(count) => Ok(count),
+    }
+  }
+
+  async fn set_layer(
+    &self,
+    node: &hidio_capnp::node::Client,
+    layer: u8,
+  ) -> Result<(), capnp::Error> {
+    crate::modules::layer::set_layer(node, layer).await?;
+    crate::modules::layer::save_layer(&self.serial, layer);
+    self.layer.set(Some(layer));
+    Ok(())
+  }
+}
+
 pub enum Commands {
-  LayerSet(u8),
+  LayerSet(Layer),
+  /// Next layer, wrapping around the given or configured layer count
+  LayerNext(Option<u8>),
+  /// Previous layer, wrapping around the given or configured layer count
+  LayerPrev(Option<u8>),
+  /// Switch to the first layer, or to the second if the first is active
+  LayerToggle(Layer, Layer),
+  /// Set a layer for a number of seconds, then go back to the previous one
+  LayerFor(Layer, u64),
+  /// Firmware CLI command, with a timeout in milliseconds
+  Cli(String, u64),
+  SleepMode,
+  FlashMode,
+  ManufacturingTest(u16, u16),
+  Info,
+}
+
+impl Commands {
+  /// One instance of every command, used to build the `exec` subcommands
+  pub fn all() -> Vec<Commands> {
+    vec![
+      Commands::LayerSet(Layer::Number(0)),
+      Commands::LayerNext(None),
+      Commands::LayerPrev(None),
+      Commands::LayerToggle(Layer::Number(0), Layer::Number(0)),
+      Commands::LayerFor(Layer::Number(0), 0),
+      Commands::Cli(String::new(), 0),
+      Commands::SleepMode,
+      Commands::FlashMode,
+      Commands::ManufacturingTest(0, 0),
+      Commands::Info,
+    ]
+  }
+
+  /// How long the command runs on purpose, on top of the time its requests take
+  pub fn duration(&self) -> Duration {
+    match self {
+      Commands::LayerFor(_, secs) => Duration::from_secs(*secs),
+      Commands::Cli(_, timeout) => Duration::from_millis(*timeout),
+      _ => Duration::ZERO,
+    }
+  }
+
+  /// Send the command to a keyboard node and describe the result
+  pub async fn run(
+    &self,
+    node: &hidio_capnp::node::Client,
+    ctx: &Context,
+  ) -> Result<String, capnp::Error> {
+    match self {
+      Commands::LayerSet(layer) => {
+        let layer = ctx.resolve(layer)?;
+        ctx.set_layer(node, layer).await?;
+        Ok(format!("LayerSet: {} request sent", layer))
+      }
+      Commands::LayerNext(count) => {
+        let count = u16::from(ctx.layer_count(*count)?);
+        let layer = ((u16::from(ctx.layer()?) + 1) % count) as u8;
+        ctx.set_layer(node, layer).await?;
+        Ok(format!("LayerNext: {} request sent", layer))
+      }
+      Commands::LayerPrev(count) => {
+        let count = u16::from(ctx.layer_count(*count)?);
+        let layer = ((u16::from(ctx.layer()?) + count - 1) % count) as u8;
+        ctx.set_layer(node, layer).await?;
+        Ok(format!("LayerPrev: {} request sent", layer))
+      }
+      Commands::LayerToggle(a, b) => {
+        let (a, b) = (ctx.resolve(a)?, ctx.resolve(b)?);
+        let layer = if ctx.layer()? == a { b } else { a };
+        ctx.set_layer(node, layer).await?;
+        Ok(format!("LayerToggle: {} request sent", layer))
+      }
+      Commands::LayerFor(layer, secs) => {
+        let layer = ctx.resolve(layer)?;
+        let prev = ctx.layer()?;
+        ctx.set_layer(node, layer).await?;
+        println!("LayerFor: {} for {}s", layer, secs);
+        tokio::time::sleep(Duration::from_secs(*secs)).await;
+        ctx.set_layer(node, prev).await?;
+        Ok(format!("LayerFor: back to {}", prev))
+      }
+      Commands::Cli(command, timeout) => cli(node, command, Duration::from_millis(*timeout)).await,
+      Commands::SleepMode => {
+        let response = node.sleep_mode_request().send().promise.await?;
+        use hidio_capnp::node::sleep_mode_status::Which;
+        match response.get()?.get_status()?.which()? {
+          Which::Success(()) => Ok("SleepMode: keyboard is going to sleep".to_string()),
+          Which::Error(e) => {
+            Err(capnp::Error::failed(format!("SleepMode: {:?}", e?.get_reason()?)))
+          }
+        }
+      }
+      Commands::FlashMode => {
+        let response = node.flash_mode_request().send().promise.await?;
+        use hidio_capnp::node::flash_mode_status::Which;
+        match response.get()?.get_status()?.which()? {
+          Which::Success(s) => {
+            Ok(format!("FlashMode: entering bootloader (scancode {})", s?.get_scan_code()))
+          }
+          Which::Error(e) => {
+            Err(capnp::Error::failed(format!("FlashMode: {:?}", e?.get_reason()?)))
+          }
+        }
+      }
+      Commands::ManufacturingTest(cmd, arg) => {
+        let mut request = node.manufacturing_test_request();
+        request.get().set_command(*cmd);
+        request.get().set_argument(*arg);
+        request.send().promise.await?;
+        Ok(format!("ManufacturingTest: {} {} request sent", cmd, arg))
+      }
+      Commands::Info => {
+        let response = node.info_request().send().promise.await?;
+        let info = response.get()?.get_info()?;
+        Ok(format!(
+          "HID-IO: {}.{}.{}\nDevice: {} {} ({})\nSerial: {}\nMCU: {}\nFirmware: {} {}",
+          info.get_hidio_major_version(),
+          info.get_hidio_minor_version(),
+          info.get_hidio_patch_version(),
+          info.get_device_vendor()?,
+          info.get_device_name()?,
+          info.get_device_version()?,
+          info.get_device_serial()?,
+          info.get_device_mcu()?,
+          info.get_firmware_name()?,
+          info.get_firmware_version()?,
+        ))
+      }
+    }
+  }
+}
+
+/// Run a firmware CLI command, streaming its output to stdout
+async fn cli(
+  node: &hidio_capnp::node::Client,
+  command: &str,
+  timeout: Duration,
+) -> Result<String, capnp::Error> {
+  // CLI output is only delivered through a keyboard subscription
+  let keyboard = keyboard_capnp::keyboard::Client {
+    client: node.client.clone(),
+  };
+  let (events_tx, mut events_rx) = tokio::sync::mpsc::unbounded_channel();
+  let mut request = keyboard.subscribe_request();
+  let mut params = request.get();
+  params.set_subscriber(capnp_rpc::new_client(KeyboardSubscriberImpl::new(
+    String::new(),
+    false,
+    events_tx,
+  )));
+  let options = params.init_options(1);
+  options.get(0).set_type(keyboard_capnp::keyboard::SubscriptionOptionType::CliOutput);
+  let subscription = request.send().promise.await?;
+
+  let mut request = node.cli_command_request();
+  request.get().set_command(command);
+  request.send().promise.await?;
+
+  let deadline = Instant::now() + timeout;
+  let mut received = false;
+  let result = loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let wait = if received {
+      CLI_IDLE.min(remaining)
+    } else {
+      remaining
+    };
+    match tokio::time::timeout(wait, events_rx.recv()).await {
+      Ok(Some((_, Event::CliOutput(output)))) => {
+        print!("{}", output);
+        std::io::stdout().flush().ok();
+        received = true;
+      }
+      Ok(Some(_)) => {}
+      Ok(None) => break Ok(format!("Cli: {} complete", command)),
+      Err(_) if received => break Ok(format!("Cli: {} complete", command)),
+      Err(_) => break Err(capnp::Error::failed(format!("Cli: {} timed out", command))),
+    }
+  };
+
+  let mut request = keyboard.unsubscribe_request();
+  request.get().set_subscription(subscription.get()?.get_subscription()?);
+  request.send().promise.await?;
+  result
 }
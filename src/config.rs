@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::endpoint::ConnectionConfig;
+use crate::modules::appwatch::AppsConfig;
+use crate::modules::hooks::Hook;
+use crate::modules::hyprland::HyprlandConfig;
+use crate::modules::snippets::Snippet;
+use crate::modules::volume::AudioRules;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// Number of layers relative layer commands wrap around
+  pub layers: Option<u8>,
+  pub hooks: Vec<Hook>,
+  pub snippets: Vec<Snippet>,
+  pub hyprland: Option<HyprlandConfig>,
+  pub apps: Option<AppsConfig>,
+  pub connection: ConnectionConfig,
+  /// Per-keyboard settings, keyed by serial
+  pub devices: BTreeMap<String, DeviceProfile>,
+}
+
+/// Settings for a single keyboard
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceProfile {
+  /// Short name accepted wherever a keyboard selector is
+  pub alias: Option<String>,
+  /// Names of the layers in layer order, accepted by layer commands in place of numbers
+  pub layer_names: Vec<String>,
+  /// Number of layers, defaults to the number of layer names and then the global `layers`
+  pub layers: Option<u8>,
+  /// Subscribe to this keyboard when `subscribe` is given no selector
+  pub subscribe: bool,
+  /// Layer to restore when the subscription shuts down
+  pub exit_layer: Option<u8>,
+  /// Hooks that only fire for this keyboard
+  pub hooks: Vec<Hook>,
+  pub audio: Option<AudioRules>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io(std::io::Error),
+  Parse(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConfigError::Io(e) => write!(f, "{}", e),
+      ConfigError::Parse(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl Config {
+  pub fn load(path: &Path) -> Result<Self, ConfigError> {
+    let raw = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+    toml::from_str(&raw).map_err(ConfigError::Parse)
+  }
+
+  /// Problems that parse fine but would fail or be ignored at runtime, prefixed with their key
+  ///
+  /// Only the key path is known here, not the line it is on.
+  pub fn validate(&self) -> Vec<String> {
+    let mut problems = Vec::new();
+    if self.layers == Some(0) {
+      problems.push("layers: must be at least 1".to_string());
+    }
+
+    let connection = &self.connection;
+    if connection.ca.is_some() && connection.pin.is_some() {
+      problems.push("connection: set either ca or pin, not both".to_string());
+    }
+    if let Some(ca) = connection.ca.as_ref().filter(|ca| !ca.is_file()) {
+      problems.push(format!("connection.ca: {} does not exist", ca.display()));
+    }
+    if let Some(key) = connection.key.as_ref().filter(|key| !key.is_file()) {
+      problems.push(format!("connection.key: {} does not exist", key.display()));
+    }
+    if let Some(pin) = &connection.pin {
+      if crate::endpoint::parse_fingerprint(pin).is_none() {
+        problems.push("connection.pin: expected a SHA-256 fingerprint in hex".to_string());
+      }
+    }
+    if connection.timeout == Some(0) {
+      problems.push("connection.timeout: must be at least 1".to_string());
+    }
+
+    let hook_problems = |key: &str, hooks: &[Hook], problems: &mut Vec<String>| {
+      for (i, hook) in hooks.iter().enumerate() {
+        if hook.run.is_empty() && hook.text.is_none() {
+          problems.push(format!("{}[{}]: needs run or text", key, i));
+        }
+      }
+    };
+    hook_problems("hooks", &self.hooks, &mut problems);
+
+    let mut aliases: BTreeMap<&str, &str> = BTreeMap::new();
+    for (serial, profile) in &self.devices {
+      let key = format!("devices.{:?}", serial);
+      if let Some(alias) = &profile.alias {
+        if alias.is_empty() || alias.contains([',', ':']) || alias == "auto" {
+          problems.push(format!("{}.alias: {:?} cannot be used as a selector", key, alias));
+        }
+        if let Some(other) = aliases.insert(alias, serial) {
+          problems.push(format!("{}.alias: {:?} is also the alias of {}", key, alias, other));
+        }
+      }
+      if profile.layers == Some(0) {
+        problems.push(format!("{}.layers: must be at least 1", key));
+      }
+      let names = profile.layer_names.len();
+      if let Some(layers) = profile.layers.filter(|n| usize::from(*n) < names) {
+        problems.push(format!("{}.layer_names: {} names for {} layers", key, names, layers));
+      }
+      if let (Some(layer), Some(count)) = (profile.exit_layer, self.layer_count(serial)) {
+        if layer >= count {
+          problems.push(format!("{}.exit_layer: {} is beyond the {} layers", key, layer, count));
+        }
+      }
+      hook_problems(&format!("{}.hooks", key), &profile.hooks, &mut problems);
+    }
+    problems
+  }
+
+  pub fn profile(&self, serial: &str) -> Option<&DeviceProfile> { self.devices.get(serial) }
+
+  /// Serial of the keyboard with the given alias
+  pub fn serial_for_alias(&self, alias: &str) -> Option<&str> {
+    self
+      .devices
+      .iter()
+      .find(|(_, profile)| profile.alias.as_deref() == Some(alias))
+      .map(|(serial, _)| serial.as_str())
+  }
+
+  /// Serials of keyboards named by serial or alias in an integration rule
+  pub fn resolve_keyboards(&self, keyboards: &[String]) -> Vec<String> {
+    keyboards.iter().map(|k| self.serial_for_alias(k).unwrap_or(k).to_string()).collect()
+  }
+
+  /// Number of layers relative layer commands wrap around on the given keyboard
+  pub fn layer_count(&self, serial: &str) -> Option<u8> {
+    let profile = self.profile(serial);
+    profile
+      .and_then(|p| p.layers)
+      .or_else(|| profile.map(|p| p.layer_names.len()).filter(|n| *n > 0).map(|n| n as u8))
+      .or(self.layers)
+  }
+
+  /// Whether the enabled integrations send commands to keyboards or type text
+  pub fn needs_privileged(&self) -> bool {
+    !self.snippets.is_empty()
+      || self.apps.is_some()
+      || self.hyprland.as_ref().is_some_and(|h| h.follow_submaps)
+      || self.all_hooks().iter().any(|h| h.text.is_some())
+      || self.devices.values().any(|p| p.exit_layer.is_some())
+  }
+
+  /// Global hooks followed by the hooks of every profile, limited to their keyboard
+  pub fn all_hooks(&self) -> Vec<Hook> {
+    let device_hooks = self.devices.iter().flat_map(|(serial, profile)| {
+      profile.hooks.iter().cloned().map(move |hook| Hook {
+        serial: Some(serial.clone()),
+        ..hook
+      })
+    });
+    self.hooks.iter().cloned().chain(device_hooks).collect()
+  }
+}
+
+/// The file given with `--config` or `HIDIOKB_CONFIG`, or the per-user config file if there is
+/// one
+pub fn path(matches: &clap::ArgMatches) -> Option<PathBuf> {
+  watch_path(matches).filter(|path| path.exists() || given(matches))
+}
+
+/// The config file to follow while subscribed, the per-user one even before it is created
+pub fn watch_path(matches: &clap::ArgMatches) -> Option<PathBuf> {
+  match matches.try_get_one::<String>("config").ok().flatten() {
+    Some(path) => Some(PathBuf::from(path)),
+    None => crate::util::config_dir().map(|dir| dir.join("config.toml")),
+  }
+}
+
+/// Whether a config file was given, not every subcommand takes `--config`
+fn given(matches: &clap::ArgMatches) -> bool {
+  matches.try_get_one::<String>("config").ok().flatten().is_some()
+}
+
+/// Load the config file of a subcommand, exiting on errors
+pub fn from_matches(matches: &clap::ArgMatches) -> Config {
+  let Some(path) = path(matches) else {
+    return Config::default();
+  };
+  match Config::load(&path) {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("Could not load config {}: {}", path.display(), e);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Check the config file of `config check`, printing every problem
+///
+/// Parse errors come with their line and column, the other problems with their key path.
+/// Returns whether the file is valid.
+pub fn check(matches: &clap::ArgMatches) -> bool {
+  let Some(path) = path(matches) else {
+    println!("No config file, using the defaults");
+    return true;
+  };
+  let config = match Config::load(&path) {
+    Ok(config) => config,
+    Err(e) => {
+      // Parse errors carry the line, column and offending snippet
+      eprintln!("{}: {}", path.display(), e);
+      return false;
+    }
+  };
+  let problems = config.validate();
+  for problem in &problems {
+    eprintln!("{}: {}", path.display(), problem);
+  }
+  if problems.is_empty() {
+    println!("{}: OK", path.display());
+  }
+  problems.is_empty()
+}
+
+/// Picks up changes to the config file by polling its modification time
+pub struct Watcher {
+  path: PathBuf,
+  modified: Option<SystemTime>,
+}
+
+impl Watcher {
+  pub fn new(path: PathBuf) -> Self {
+    let modified = modified(&path);
+    Self { path, modified }
+  }
+
+  /// The new config if the file changed since the last call, `None` if it did not or is invalid
+  ///
+  /// Problems are reported here, the caller keeps its current config then. A file that does not
+  /// exist yet is picked up once it is created.
+  pub fn poll(&mut self) -> Option<Config> {
+    let modified = modified(&self.path);
+    if modified == self.modified {
+      return None;
+    }
+    self.modified = modified;
+    if modified.is_none() {
+      eprintln!("Keeping the current config, {} was removed", self.path.display());
+      return None;
+    }
+    let config = match Config::load(&self.path) {
+      Ok(config) => config,
+      Err(e) => {
+        eprintln!("Keeping the current config, {} is invalid: {}", self.path.display(), e);
+        return None;
+      }
+    };
+    let problems = config.validate();
+    if !problems.is_empty() {
+      eprintln!("Keeping the current config, {} is invalid:", self.path.display());
+      problems.iter().for_each(|problem| eprintln!("  {}", problem));
+      return None;
+    }
+    println!("Reloaded config {}", self.path.display());
+    Some(config)
+  }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> { std::fs::metadata(path).ok()?.modified().ok() }
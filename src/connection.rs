@@ -0,0 +1,253 @@
+use std::fmt;
+use std::time::Duration;
+
+use hid_io_client::common_capnp::NodeType;
+use hid_io_core::hidio_capnp;
+use rand::Rng;
+
+use crate::config::Config;
+use crate::endpoint::Endpoint;
+
+/// Longest pause between two connection attempts
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum ConnectionError {
+  /// The client could not be set up, e.g. the TLS configuration failed to load
+  Setup(std::io::Error),
+  Connect(capnp::Error),
+  /// Connecting and authenticating took longer than the endpoint timeout
+  Timeout(Duration),
+  /// hid-io-core accepted the connection but refused to authenticate at this level
+  Auth(AuthLevel),
+  /// A request on an established connection failed
+  Rpc(capnp::Error),
+  /// Gave up after the configured number of attempts, with the last error
+  RetriesExhausted(u32, Box<ConnectionError>),
+}
+
+impl fmt::Display for ConnectionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConnectionError::Setup(e) => write!(f, "Could not set up hid-io-core client: {}", e),
+      ConnectionError::Connect(e) => write!(f, "Could not connect to hid-io-core: {}", e),
+      ConnectionError::Timeout(timeout) => {
+        write!(f, "hid-io-core did not answer within {}ms", timeout.as_millis())
+      }
+      ConnectionError::Auth(AuthLevel::Basic) => write!(
+        f,
+        "hid-io-core refused basic authentication, check that it runs as the same user and that \
+         its basic key file is readable"
+      ),
+      ConnectionError::Auth(AuthLevel::Privileged) => write!(
+        f,
+        "hid-io-core refused privileged authentication. This command needs privileged access, \
+         which requires read access to the auth key file of hid-io-core; list, wait-for and \
+         subscribe without snippets, text hooks or layer following only need basic access \
+         (--auth basic)"
+      ),
+      ConnectionError::Rpc(e) => write!(f, "hid-io-core request failed: {}", e),
+      ConnectionError::RetriesExhausted(attempts, e) => {
+        write!(f, "Giving up after {} attempts: {}", attempts, e)
+      }
+    }
+  }
+}
+
+impl From<capnp::Error> for ConnectionError {
+  fn from(e: capnp::Error) -> Self { ConnectionError::Rpc(e) }
+}
+
+/// Connection state changes reported by the supervisor
+pub enum ConnectionState<'a> {
+  Connecting,
+  Connected,
+  Lost(&'a ConnectionError),
+  /// Attempt number, delay before it and the error that caused it
+  Retrying(u32, Duration, &'a ConnectionError),
+  Failed(&'a ConnectionError),
+}
+
+impl fmt::Display for ConnectionState<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConnectionState::Connecting => write!(f, "hid-io-core: connecting"),
+      ConnectionState::Connected => write!(f, "hid-io-core: connected"),
+      ConnectionState::Lost(e) => write!(f, "hid-io-core: connection lost - {}", e),
+      ConnectionState::Retrying(attempt, delay, e) => write!(
+        f,
+        "hid-io-core: {}, retrying in {:.1}s (attempt {})",
+        e,
+        delay.as_secs_f32(),
+        attempt
+      ),
+      ConnectionState::Failed(e) => write!(f, "hid-io-core: failed - {}", e),
+    }
+  }
+}
+
+/// Authentication level requested from hid-io-core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthLevel {
+  /// Enough to list nodes and receive keyboard events
+  Basic,
+  /// Needed to send commands to keyboards and type through the daemon node
+  Privileged,
+}
+
+impl AuthLevel {
+  /// Lowest level a subcommand needs, given the features its config enables
+  pub fn required(subcommand: &str, config: &Config) -> Self {
+    match subcommand {
+      "list" | "wait-for" => AuthLevel::Basic,
+      "subscribe" | "daemon" if !config.needs_privileged() => AuthLevel::Basic,
+      // Including info, whose firmware details are a request to the keyboard node, which
+      // hid-io-core only answers for privileged clients
+      _ => AuthLevel::Privileged,
+    }
+  }
+}
+
+/// How often and how fast to retry a failed connection
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  /// Delay before the first retry, doubled on every further attempt up to `MAX_DELAY`
+  pub initial_delay: Duration,
+  /// Give up after this many failed attempts, retry forever when unset
+  pub max_retries: Option<u32>,
+}
+
+impl RetryPolicy {
+  /// Randomised delay before retry number `attempt`, counting from 1
+  pub fn delay(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+    let exp = self.initial_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+    // Spread reconnecting clients out so they do not hit a restarting daemon at once
+    exp.min(MAX_DELAY).mul_f64(rng.gen_range(0.5..=1.0))
+  }
+}
+
+/// An authenticated hid-io-core session
+pub struct Connection {
+  pub auth: hidio_capnp::hidio::Client,
+  pub server: hidio_capnp::hid_io_server::Client,
+}
+
+/// Owns the hid-io-core client and re-establishes its session with backoff
+pub struct Supervisor {
+  conn: hid_io_client::HidioConnection,
+  endpoint: Endpoint,
+  level: AuthLevel,
+  policy: RetryPolicy,
+  rng: rand::rngs::ThreadRng,
+  on_state: Box<dyn Fn(&ConnectionState)>,
+  /// Failed attempts since the last session that became usable
+  attempt: u32,
+  /// Failure of a session that was set up but not usable, retried by the next `connect`
+  failure: Option<ConnectionError>,
+}
+
+impl Supervisor {
+  pub fn new(
+    endpoint: Endpoint,
+    level: AuthLevel,
+    policy: RetryPolicy,
+  ) -> Result<Self, ConnectionError> {
+    Ok(Self {
+      conn: hid_io_client::HidioConnection::new().map_err(ConnectionError::Setup)?,
+      endpoint,
+      level,
+      policy,
+      rng: rand::thread_rng(),
+      // Only problems are reported unless the caller asks for every state
+      on_state: Box::new(|state| {
+        if !matches!(state, ConnectionState::Connecting | ConnectionState::Connected) {
+          eprintln!("{}", state);
+        }
+      }),
+      attempt: 0,
+      failure: None,
+    })
+  }
+
+  /// Report connection state changes through `f` instead of printing problems to stderr
+  pub fn on_state(&mut self, f: impl Fn(&ConnectionState) + 'static) {
+    self.on_state = Box::new(f);
+  }
+
+  async fn connect_once(&mut self) -> Result<Connection, ConnectionError> {
+    let serial = format!("{:x} - pid:{}", self.rng.gen::<u64>(), std::process::id());
+    let (timeout, level) = (self.endpoint.timeout, self.level);
+    let endpoint = &self.endpoint;
+    let conn = &mut self.conn;
+    let connect = async move {
+      // The bundled client is kept for the default endpoint, it cannot be pointed elsewhere
+      if endpoint.is_default() {
+        let auth_type = match level {
+          AuthLevel::Basic => hid_io_client::AuthType::Basic,
+          AuthLevel::Privileged => hid_io_client::AuthType::Priviledged,
+        };
+        let (auth, server) = conn
+          .connect(
+            auth_type,
+            NodeType::HidioApi,
+            endpoint.name.clone(),
+            serial,
+            false,
+            Duration::from_millis(1000),
+          )
+          .await
+          .map_err(ConnectionError::Connect)?;
+        Ok((auth.ok_or(ConnectionError::Auth(level))?, server))
+      } else {
+        endpoint.connect(level, NodeType::HidioApi, serial).await
+      }
+    };
+    let (auth, server) = tokio::time::timeout(timeout, connect)
+      .await
+      .map_err(|_| ConnectionError::Timeout(timeout))??;
+    Ok(Connection { auth, server })
+  }
+
+  /// Connect and authenticate, retrying failed attempts according to the policy
+  ///
+  /// Authentication failures are not retried, since they do not go away by themselves.
+  pub async fn connect(&mut self) -> Result<Connection, ConnectionError> {
+    (self.on_state)(&ConnectionState::Connecting);
+    let mut failure = self.failure.take();
+    loop {
+      if let Some(e) = failure.take() {
+        self.attempt += 1;
+        if self.policy.max_retries.is_some_and(|max| self.attempt > max) {
+          let e = ConnectionError::RetriesExhausted(self.attempt, Box::new(e));
+          (self.on_state)(&ConnectionState::Failed(&e));
+          return Err(e);
+        }
+        let delay = self.policy.delay(self.attempt, &mut self.rng);
+        (self.on_state)(&ConnectionState::Retrying(self.attempt, delay, &e));
+        tokio::time::sleep(delay).await;
+      }
+      match self.connect_once().await {
+        Ok(connection) => {
+          (self.on_state)(&ConnectionState::Connected);
+          return Ok(connection);
+        }
+        Err(e @ ConnectionError::Auth(_)) => {
+          (self.on_state)(&ConnectionState::Failed(&e));
+          return Err(e);
+        }
+        Err(e) => failure = Some(e),
+      }
+    }
+  }
+
+  /// The session from the last `connect` answered requests, so later failures start a new count
+  pub fn established(&mut self) { self.attempt = 0; }
+
+  /// Report a session that failed before it became usable
+  ///
+  /// The next `connect` waits before trying again and counts it against the retry limit.
+  pub fn failed(&mut self, e: ConnectionError) { self.failure = Some(e); }
+
+  /// Report a connection that dropped; the caller reconnects with `connect`
+  pub fn lost(&self, e: &ConnectionError) { (self.on_state)(&ConnectionState::Lost(e)); }
+}
@@ -0,0 +1,297 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use hid_io_client::common_capnp::destination;
+use hid_io_core::hidio_capnp;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::commands::{Commands, Context};
+use crate::config::Config;
+use crate::connection::AuthLevel;
+use crate::session::Keyboards;
+
+/// Subcommands a running instance answers on behalf of other invocations
+pub const FORWARDED: &[&str] = &["exec", "status"];
+/// Exit status of `status` when no instance is running, as with `systemctl status`
+pub const EXIT_NOT_RUNNING: i32 = 3;
+/// How long a forwarded command may take, on top of the time it runs on purpose
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lock file and control socket, in the per-user runtime directory
+fn paths() -> Option<(PathBuf, PathBuf)> {
+  let dir = crate::util::runtime_dir()?;
+  Some((dir.join("instance.lock"), dir.join("control.sock")))
+}
+
+#[derive(Debug)]
+pub enum LockError {
+  /// Another instance holds the lock, with its pid when known
+  Running(Option<u32>),
+  Io(std::io::Error),
+}
+
+impl fmt::Display for LockError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LockError::Running(pid) => {
+        write!(f, "hidiokb is already running")?;
+        if let Some(pid) = pid {
+          write!(f, " (pid {})", pid)?;
+        }
+        write!(f, ", control it with hidiokb exec or hidiokb status")
+      }
+      LockError::Io(e) => write!(f, "Could not set up the control socket: {}", e),
+    }
+  }
+}
+
+impl From<std::io::Error> for LockError {
+  fn from(e: std::io::Error) -> Self { LockError::Io(e) }
+}
+
+/// Answer to a forwarded command line
+enum Reply {
+  Output(String),
+  Failed(String),
+  /// The instance cannot run this command, the caller runs it over its own connection
+  Pass,
+}
+
+/// hid-io-core session of the running instance, replaced on every reconnect
+struct Attached {
+  auth: hidio_capnp::hidio::Client,
+  keyboards: Keyboards,
+}
+
+/// Per-user instance lock, and the control socket other invocations are served on
+#[derive(Clone)]
+pub struct Control {
+  attached: Rc<RefCell<Option<Attached>>>,
+  config: Rc<RefCell<Config>>,
+  /// Level the instance authenticated at, commands needing more are passed back
+  level: AuthLevel,
+  /// Held until the process exits, the kernel releases the lock of a crashed instance
+  _lock: Rc<File>,
+}
+
+impl Control {
+  /// Take the instance lock and start serving the control socket
+  pub fn bind(config: Config, level: AuthLevel) -> Result<Self, LockError> {
+    let (lock_path, socket_path) = paths().ok_or_else(|| {
+      std::io::Error::new(std::io::ErrorKind::NotFound, "No runtime directory, HOME is not set")
+    })?;
+    if let Some(dir) = lock_path.parent() {
+      std::fs::create_dir_all(dir)?;
+      std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    let mut lock =
+      OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_path)?;
+    match lock.try_lock() {
+      Ok(()) => {}
+      Err(TryLockError::WouldBlock) => {
+        let pid = std::fs::read_to_string(&lock_path).ok().and_then(|p| p.trim().parse().ok());
+        return Err(LockError::Running(pid));
+      }
+      Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    lock.set_len(0)?;
+    write!(lock, "{}", std::process::id())?;
+
+    // Left behind by an instance that did not shut down cleanly
+    std::fs::remove_file(&socket_path).ok();
+    let listener = UnixListener::bind(&socket_path)?;
+    let control = Self {
+      attached: Rc::new(RefCell::new(None)),
+      config: Rc::new(RefCell::new(config)),
+      level,
+      _lock: Rc::new(lock),
+    };
+    let server = control.clone();
+    tokio::task::spawn_local(async move {
+      loop {
+        match listener.accept().await {
+          Ok((stream, _)) => {
+            let server = server.clone();
+            tokio::task::spawn_local(async move {
+              if let Err(e) = server.serve(stream).await {
+                eprintln!("Control request failed: {}", e);
+              }
+            });
+          }
+          Err(e) => {
+            eprintln!("Control socket failed: {}", e);
+            break;
+          }
+        }
+      }
+    });
+    Ok(control)
+  }
+
+  /// Serve requests over a newly established hid-io-core session
+  pub fn attach(&self, auth: hidio_capnp::hidio::Client, keyboards: Keyboards) {
+    *self.attached.borrow_mut() = Some(Attached { auth, keyboards });
+  }
+
+  /// Use a reloaded config for requests from now on
+  pub fn set_config(&self, config: Config) { *self.config.borrow_mut() = config; }
+
+  /// Forget the session after the connection to hid-io-core was lost
+  pub fn detach(&self) { *self.attached.borrow_mut() = None; }
+
+  /// Answer one request: a command line, replied to with `out` and `err` lines and a final
+  /// `exit` line, or a single `pass` line
+  async fn serve(&self, stream: UnixStream) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+    let (kind, text, code) = match self.handle(line.trim_end()).await {
+      Reply::Output(output) => ("out", output, 0),
+      Reply::Failed(e) => ("err", e, 1),
+      Reply::Pass => return writer.write_all(b"pass\n").await,
+    };
+    let mut reply: String = text.lines().map(|l| format!("{} {}\n", kind, l)).collect();
+    reply.push_str(&format!("exit {}\n", code));
+    writer.write_all(reply.as_bytes()).await
+  }
+
+  async fn handle(&self, line: &str) -> Reply {
+    let Some(argv) = shlex::split(line) else {
+      return Reply::Failed("Malformed request".to_string());
+    };
+    let matches = match crate::args::cli()
+      .try_get_matches_from(std::iter::once("hidiokb".to_string()).chain(argv))
+    {
+      Ok(matches) => matches,
+      Err(e) => return Reply::Failed(e.to_string()),
+    };
+    let result = match matches.subcommand() {
+      Some(("status", _)) => Ok(self.status()),
+      // The session of this instance was set up with its own options, not the ones given
+      Some(("exec", _)) if crate::args::overrides_connection(&matches) => return Reply::Pass,
+      // Keyboard commands need a privileged session, which a basic subscription does not have
+      Some(("exec", _)) if self.level == AuthLevel::Basic => return Reply::Pass,
+      // Output printed while the command runs would end up on the terminal of this instance
+      Some(("exec", sub_matches)) if streams(sub_matches) => return Reply::Pass,
+      Some(("exec", sub_matches)) => self.exec(sub_matches).await,
+      _ => return Reply::Pass,
+    };
+    match result {
+      Ok(output) => Reply::Output(output),
+      Err(e) => Reply::Failed(e),
+    }
+  }
+
+  fn status(&self) -> String {
+    let mut lines = vec![format!("Running (pid {})", std::process::id())];
+    match &*self.attached.borrow() {
+      Some(attached) => {
+        lines.push("hid-io-core: connected".to_string());
+        let keyboards = attached.keyboards.status();
+        if keyboards.is_empty() {
+          lines.push("Waiting for a matching keyboard".to_string());
+        }
+        lines.extend(keyboards);
+      }
+      None => lines.push("hid-io-core: not connected".to_string()),
+    }
+    if self.level == AuthLevel::Basic {
+      lines.push("Basic access: exec commands connect to hid-io-core themselves".to_string());
+    }
+    lines.join("\n")
+  }
+
+  /// Run an `exec` command over the session of this instance
+  async fn exec(&self, matches: &ArgMatches) -> Result<String, String> {
+    let (auth, keyboards) = match &*self.attached.borrow() {
+      Some(attached) => (attached.auth.clone(), attached.keyboards.clone()),
+      None => return Err("Not connected to hid-io-core, try again shortly".to_string()),
+    };
+    let (name, command_matches) = matches.subcommand().ok_or("No command given")?;
+    let command = Commands::try_from((name, command_matches))?;
+    let selector = crate::args::selector(matches, &self.config.borrow())?;
+
+    let rpc = |e: capnp::Error| e.to_string();
+    let nodes_resp = auth.nodes_request().send().promise.await.map_err(rpc)?;
+    let nodes = nodes_resp.get().and_then(|r| r.get_nodes()).map_err(rpc)?;
+    // Nobody can answer a prompt on the terminal of the running instance
+    let device = crate::device::select(nodes, &selector, false)?;
+    let node = match device.get_node().which().map_err(|e| rpc(e.into()))? {
+      destination::node::Which::Keyboard(n) => hidio_capnp::node::Client {
+        client: n.map_err(rpc)?.client,
+      },
+      destination::node::Which::Daemon(_) => return Err("Not a keyboard node".to_string()),
+    };
+    let serial = device.get_serial().map_err(rpc)?.to_string();
+    let ctx = Context::new(serial.clone(), &self.config.borrow());
+    // The live layer of a subscribed keyboard is more recent than the recorded one
+    if let Some(layer) = keyboards.layer(&serial) {
+      ctx.layer.set(Some(layer));
+    }
+    command.run(&node, &ctx).await.map_err(rpc)
+  }
+}
+
+/// Whether an `exec` command prints progress while it runs instead of only returning its result
+fn streams(matches: &ArgMatches) -> bool {
+  let command = matches.subcommand().and_then(|command| Commands::try_from(command).ok());
+  matches!(command, Some(Commands::Cli(..) | Commands::LayerFor(..)))
+}
+
+/// Time to wait for a forwarded command line, from its parsed arguments
+pub fn forward_timeout(matches: &ArgMatches) -> Duration {
+  let duration = match matches.subcommand() {
+    Some(("exec", sub_matches)) => sub_matches
+      .subcommand()
+      .and_then(|command| Commands::try_from(command).ok())
+      .map_or(Duration::ZERO, |command| command.duration()),
+    _ => Duration::ZERO,
+  };
+  FORWARD_TIMEOUT + duration
+}
+
+/// Run a command line through the running instance, returning its exit status
+///
+/// `None` when no instance is running or it cannot run the command, in which case the caller
+/// connects to hid-io-core itself. An instance that does not answer within `timeout` counts as
+/// failed.
+pub async fn forward(argv: &[String], timeout: Duration) -> Option<i32> {
+  match tokio::time::timeout(timeout, forward_once(argv)).await {
+    Ok(code) => code,
+    Err(_) => {
+      eprintln!("The running instance did not answer within {}s", timeout.as_secs());
+      Some(1)
+    }
+  }
+}
+
+async fn forward_once(argv: &[String]) -> Option<i32> {
+  let (_, socket_path) = paths()?;
+  let stream = UnixStream::connect(socket_path).await.ok()?;
+  let (reader, mut writer) = stream.into_split();
+  let request = shlex::try_join(argv.iter().map(String::as_str)).ok()?;
+  writer.write_all(format!("{}\n", request).as_bytes()).await.ok()?;
+
+  let mut lines = BufReader::new(reader).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    if line == "pass" {
+      return None;
+    }
+    match line.split_once(' ') {
+      Some(("out", text)) => println!("{}", text),
+      Some(("err", text)) => eprintln!("{}", text),
+      Some(("exit", code)) => return Some(code.parse().unwrap_or(1)),
+      _ => {}
+    }
+  }
+  eprintln!("The running instance closed the connection without an answer");
+  Some(1)
+}
@@ -0,0 +1,459 @@
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::str::FromStr;
+
+use hid_io_client::common_capnp::{destination, NodeType};
+
+use crate::config::Config;
+
+pub fn is_keyboard(node: &destination::Reader) -> bool {
+  matches!(node.get_type(), Ok(NodeType::UsbKeyboard) | Ok(NodeType::BleKeyboard))
+}
+
+#[derive(Clone, Debug)]
+enum Term {
+  /// Exact serial or exact name
+  Bare(String),
+  Serial(String),
+  /// Alias from a device profile, replaced by its serial before matching
+  Alias(String),
+  Name(String),
+  Regex(regex::Regex),
+  Type(String),
+  Id(u64),
+  /// Position among the attached keyboards, as shown by `list`
+  Index(usize),
+}
+
+/// Expression picking keyboards out of the hid-io-core node list
+///
+/// Either `auto` or a comma separated list of terms that must all match: `serial:S`,
+/// `alias:A`, `name:GLOB`, `regex:RE` (on the name), `type:usb|ble`, `id:N`, `index:N`, or a bare
+/// value matching an alias, serial or name exactly. A comma inside a value, e.g. in a regex, is
+/// written `\,`. `auto` picks the keyboard when only one is attached.
+#[derive(Clone, Debug)]
+pub struct Selector {
+  source: String,
+  terms: Vec<Term>,
+}
+
+impl Selector {
+  pub fn auto() -> Self {
+    Self {
+      source: "auto".to_string(),
+      terms: Vec::new(),
+    }
+  }
+
+  pub fn serial(serial: &str) -> Self {
+    Self {
+      source: format!("serial:{}", serial),
+      terms: vec![Term::Serial(serial.to_string())],
+    }
+  }
+
+  pub fn name(name: &str) -> Self {
+    Self {
+      source: format!("name:{}", name),
+      terms: vec![Term::Name(name.to_string())],
+    }
+  }
+
+  /// Selector matching keyboards picked by both `self` and `other`
+  pub fn and(mut self, other: Selector) -> Self {
+    self.source = format!("{},{}", self.source, other.source);
+    self.terms.extend(other.terms);
+    self
+  }
+
+  pub fn is_auto(&self) -> bool { self.terms.is_empty() }
+
+  /// Replace the aliases of device profiles with the serials they stand for
+  pub fn resolve_aliases(mut self, config: &Config) -> Result<Self, String> {
+    for term in self.terms.iter_mut() {
+      let serial = match term {
+        Term::Alias(alias) => match config.serial_for_alias(alias) {
+          Some(serial) => serial,
+          None => return Err(format!("Unknown keyboard alias {}", alias)),
+        },
+        Term::Bare(value) => match config.serial_for_alias(value) {
+          Some(serial) => serial,
+          None => continue,
+        },
+        _ => continue,
+      };
+      *term = Term::Serial(serial.to_string());
+    }
+    Ok(self)
+  }
+
+  fn matches(&self, index: usize, node: &destination::Reader) -> bool {
+    let serial = node.get_serial().unwrap_or_default();
+    let name = node.get_name().unwrap_or_default();
+    self.terms.iter().all(|term| match term {
+      Term::Bare(v) => serial == v || name == v,
+      Term::Serial(v) => serial == v,
+      // Left over when aliases were not resolved, so nothing can match
+      Term::Alias(_) => false,
+      Term::Name(v) => glob_match(v, name),
+      Term::Regex(re) => re.is_match(name),
+      Term::Type(v) => type_name(node).eq_ignore_ascii_case(v),
+      Term::Id(id) => node.get_id() == *id,
+      Term::Index(i) => index == *i,
+    })
+  }
+}
+
+impl FromStr for Selector {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if s.is_empty() || s == "auto" {
+      return Ok(Selector::auto());
+    }
+    let terms = split_terms(s)
+      .iter()
+      .map(|term| match term.split_once(':') {
+        Some(("serial", v)) => Ok(Term::Serial(v.to_string())),
+        Some(("alias", v)) => Ok(Term::Alias(v.to_string())),
+        Some(("name", v)) => Ok(Term::Name(v.to_string())),
+        Some(("regex", v)) => {
+          regex::Regex::new(v).map(Term::Regex).map_err(|e| format!("Invalid regex {}: {}", v, e))
+        }
+        Some(("type", v)) if v.eq_ignore_ascii_case("usb") || v.eq_ignore_ascii_case("ble") => {
+          Ok(Term::Type(v.to_string()))
+        }
+        Some(("type", v)) => Err(format!("Unknown keyboard type {}, expected usb or ble", v)),
+        Some(("id", v)) => v.parse().map(Term::Id).map_err(|_| format!("Invalid node id {}", v)),
+        Some(("index", v)) => {
+          v.parse().map(Term::Index).map_err(|_| format!("Invalid index {}", v))
+        }
+        _ => Ok(Term::Bare(term.to_string())),
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(Self {
+      source: s.to_string(),
+      terms,
+    })
+  }
+}
+
+/// Split a selector on the commas between terms, `\,` standing for a comma within a term
+fn split_terms(s: &str) -> Vec<String> {
+  let mut terms = vec![String::new()];
+  let mut chars = s.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '\\' if chars.peek() == Some(&',') => terms.last_mut().unwrap().push(chars.next().unwrap()),
+      ',' => terms.push(String::new()),
+      c => terms.last_mut().unwrap().push(c),
+    }
+  }
+  terms
+}
+
+impl fmt::Display for Selector {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.source) }
+}
+
+fn keyboards(
+  nodes: capnp::struct_list::Reader<'_, destination::Owned>,
+) -> Vec<destination::Reader<'_>> {
+  nodes.iter().filter(is_keyboard).collect()
+}
+
+/// Keyboards with their index among all attached keyboards, as shown by `list`
+type Indexed<'a> = (usize, destination::Reader<'a>);
+
+fn candidates(keyboards: &[Indexed]) -> String {
+  keyboards
+    .iter()
+    .map(|(i, n)| {
+      format!(
+        "\n  [{}] {} {} ({})",
+        i,
+        n.get_serial().unwrap_or_default(),
+        n.get_name().unwrap_or_default(),
+        type_name(n)
+      )
+    })
+    .collect()
+}
+
+/// Serial of the keyboard remembered as the default from the picker
+fn default_serial() -> Option<String> {
+  let path = crate::util::state_dir()?.join("default-keyboard");
+  let serial = std::fs::read_to_string(path).ok()?.trim().to_string();
+  (!serial.is_empty()).then_some(serial)
+}
+
+fn remember_default(serial: &str) {
+  let Some(dir) = crate::util::state_dir() else {
+    return;
+  };
+  let result = std::fs::create_dir_all(&dir)
+    .and_then(|_| std::fs::write(dir.join("default-keyboard"), serial));
+  if let Err(e) = result {
+    eprintln!("Could not remember default keyboard: {}", e);
+  }
+}
+
+/// Ask on the terminal which of `keyboards` to use, `None` if the user gave up
+fn pick<'a>(keyboards: &[Indexed<'a>]) -> Option<destination::Reader<'a>> {
+  let read_line = |prompt: &str| -> Option<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush().ok();
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+      Ok(0) | Err(_) => None,
+      Ok(_) => Some(line.trim().to_string()),
+    }
+  };
+
+  let indexes: Vec<String> = keyboards.iter().map(|(i, _)| i.to_string()).collect();
+  eprintln!("Several keyboards match:{}", candidates(keyboards));
+  let chosen = loop {
+    let line = read_line(&format!("Keyboard [{}]: ", indexes.join("/")))?;
+    if line.is_empty() {
+      return None;
+    }
+    let index = line.parse::<usize>().ok();
+    match keyboards.iter().find(|(i, _)| Some(*i) == index) {
+      Some((_, keyboard)) => break *keyboard,
+      None => eprintln!("Enter one of {}", indexes.join(", ")),
+    }
+  };
+  if let Some(answer) = read_line("Remember as the default keyboard? [y/N]: ") {
+    if answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes") {
+      remember_default(chosen.get_serial().unwrap_or_default());
+    }
+  }
+  Some(chosen)
+}
+
+/// The single keyboard picked by `selector`
+///
+/// When several keyboards match, `auto` prefers the remembered default keyboard; otherwise the
+/// user is asked to pick one if `interactive` is set and stdin is a terminal. The error lists the
+/// candidates when nothing or more than one keyboard matches.
+pub fn select<'a>(
+  nodes: capnp::struct_list::Reader<'a, destination::Owned>,
+  selector: &Selector,
+  interactive: bool,
+) -> Result<destination::Reader<'a>, String> {
+  let keyboards: Vec<Indexed> = keyboards(nodes).into_iter().enumerate().collect();
+  if keyboards.is_empty() {
+    return Err("No keyboards attached".to_string());
+  }
+  let matched: Vec<Indexed> =
+    keyboards.iter().filter(|(i, n)| selector.matches(*i, n)).copied().collect();
+  match matched.len() {
+    1 => return Ok(matched[0].1),
+    0 => {
+      return Err(format!(
+        "No keyboard matches {}. Candidates:{}",
+        selector,
+        candidates(&keyboards)
+      ))
+    }
+    _ => {}
+  }
+
+  if selector.is_auto() {
+    let default = default_serial();
+    if let Some((_, n)) = matched.iter().find(|(_, n)| n.get_serial().ok() == default.as_deref()) {
+      return Ok(*n);
+    }
+  }
+  if interactive && std::io::stdin().is_terminal() && std::io::stderr().is_terminal() {
+    return pick(&matched).ok_or_else(|| "No keyboard selected".to_string());
+  }
+  if selector.is_auto() {
+    Err(format!(
+      "Several keyboards attached, pick one with --keyboard. Candidates:{}",
+      candidates(&keyboards)
+    ))
+  } else {
+    Err(format!("{} matches {} keyboards:{}", selector, matched.len(), candidates(&matched)))
+  }
+}
+
+/// Keyboards matching any of `selectors`, or every keyboard when `all` is set
+///
+/// An `auto` selector only matches while exactly one keyboard is attached, or the remembered
+/// default keyboard when there are several.
+pub fn find_kbs<'a>(
+  nodes: capnp::struct_list::Reader<'a, destination::Owned>,
+  selectors: &[Selector],
+  all: bool,
+) -> Vec<destination::Reader<'a>> {
+  let keyboards = keyboards(nodes);
+  let count = keyboards.len();
+  let default = default_serial();
+  let auto = |n: &destination::Reader| count == 1 || n.get_serial().ok() == default.as_deref();
+  keyboards
+    .into_iter()
+    .enumerate()
+    .filter(|(i, n)| all || selectors.iter().any(|s| s.matches(*i, n) && (!s.is_auto() || auto(n))))
+    .map(|(_, n)| n)
+    .collect()
+}
+
+/// Serial `auto` stands for: the only keyboard attached, or the remembered default keyboard
+fn auto_serial(keyboards: &[Indexed]) -> Option<String> {
+  let serials: Vec<&str> = keyboards.iter().filter_map(|(_, n)| n.get_serial().ok()).collect();
+  match serials[..] {
+    [serial] => Some(serial.to_string()),
+    _ => default_serial().filter(|default| serials.contains(&default.as_str())),
+  }
+}
+
+/// Replace an `auto` selector with the serial of the keyboard it stands for
+///
+/// When several keyboards are attached and none is the remembered default, the user is asked to
+/// pick one when stdin is a terminal and the error lists the candidates otherwise. With no
+/// keyboard attached `auto` is kept, for `pin_auto` to resolve once one is plugged in.
+pub fn resolve_auto(
+  nodes: capnp::struct_list::Reader<'_, destination::Owned>,
+  selectors: &mut [Selector],
+) -> Result<(), String> {
+  let Some(auto) = selectors.iter_mut().find(|s| s.is_auto()) else {
+    return Ok(());
+  };
+  let keyboards: Vec<Indexed> = keyboards(nodes).into_iter().enumerate().collect();
+  if keyboards.is_empty() {
+    return Ok(());
+  }
+  if let Some(serial) = auto_serial(&keyboards) {
+    *auto = Selector::serial(&serial);
+    return Ok(());
+  }
+  if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+    return Err(format!(
+      "Several keyboards attached, pick one with --keyboard. Candidates:{}",
+      candidates(&keyboards)
+    ));
+  }
+  let chosen = pick(&keyboards).ok_or_else(|| "No keyboard selected".to_string())?;
+  *auto = Selector::serial(chosen.get_serial().unwrap_or_default());
+  Ok(())
+}
+
+/// Replace an `auto` selector left by `resolve_auto` once it stands for a single keyboard
+///
+/// Never asks, so a keyboard plugged in next to another one does not take over the subscription.
+pub fn pin_auto(
+  nodes: capnp::struct_list::Reader<'_, destination::Owned>,
+  selectors: &mut [Selector],
+) {
+  let Some(auto) = selectors.iter_mut().find(|s| s.is_auto()) else {
+    return;
+  };
+  let keyboards: Vec<Indexed> = keyboards(nodes).into_iter().enumerate().collect();
+  if let Some(serial) = auto_serial(&keyboards) {
+    *auto = Selector::serial(&serial);
+  }
+}
+
+/// Short transport name of a keyboard node
+pub fn type_name(node: &destination::Reader) -> &'static str {
+  match node.get_type() {
+    Ok(NodeType::UsbKeyboard) => "USB",
+    Ok(NodeType::BleKeyboard) => "BLE",
+    _ => "other",
+  }
+}
+
+/// Match `text` against a glob pattern supporting `*` and `?`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let text: Vec<char> = text.chars().collect();
+  let (mut p, mut t) = (0, 0);
+  // Position of the last `*` and the text index it is currently matched up to
+  let mut star: Option<(usize, usize)> = None;
+  while t < text.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+      p += 1;
+      t += 1;
+    } else if p < pattern.len() && pattern[p] == '*' {
+      star = Some((p, t));
+      p += 1;
+    } else if let Some((sp, st)) = star {
+      p = sp + 1;
+      t = st + 1;
+      star = Some((sp, st + 1));
+    } else {
+      return false;
+    }
+  }
+  pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::DeviceProfile;
+
+  #[test]
+  fn glob_wildcards() {
+    assert!(glob_match("Ergo*", "ErgoDox"));
+    assert!(glob_match("*Dox", "ErgoDox"));
+    assert!(glob_match("Er?o*x", "ErgoDox"));
+    assert!(glob_match("Ergo*", "Ergo"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("*o*o*", "ErgoDox"));
+    assert!(!glob_match("Ergo?", "Ergo"));
+    assert!(!glob_match("*Pad", "ErgoDox"));
+    assert!(!glob_match("ergo*", "ErgoDox"));
+  }
+
+  #[test]
+  fn parse_terms() {
+    let selector: Selector = "type:usb,name:K-Type*".parse().unwrap();
+    assert_eq!(selector.terms.len(), 2);
+    assert!(matches!(&selector.terms[0], Term::Type(t) if t == "usb"));
+    assert!(matches!(selector.terms[1], Term::Name(_)));
+    assert!("auto".parse::<Selector>().unwrap().is_auto());
+    assert!(matches!("index:2".parse::<Selector>().unwrap().terms[0], Term::Index(2)));
+    assert!(matches!("id:12".parse::<Selector>().unwrap().terms[0], Term::Id(12)));
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert_eq!("index:x".parse::<Selector>().unwrap_err(), "Invalid index x");
+    assert_eq!("index:-1".parse::<Selector>().unwrap_err(), "Invalid index -1");
+    assert_eq!("id:".parse::<Selector>().unwrap_err(), "Invalid node id ");
+    assert!("type:serial".parse::<Selector>().is_err());
+    assert!("regex:(".parse::<Selector>().is_err());
+  }
+
+  #[test]
+  fn escaped_comma_stays_in_term() {
+    let selector: Selector = r"regex:^Ergo(One|Two){1\,2}$,type:ble".parse().unwrap();
+    assert_eq!(selector.terms.len(), 2);
+    assert!(matches!(&selector.terms[0], Term::Regex(re) if re.as_str() == "^Ergo(One|Two){1,2}$"));
+  }
+
+  #[test]
+  fn aliases_resolve_to_serials() {
+    let mut config = Config::default();
+    let profile = DeviceProfile {
+      alias: Some("desk".to_string()),
+      ..Default::default()
+    };
+    config.devices.insert("S123".to_string(), profile);
+
+    let selector: Selector = "alias:desk".parse().unwrap();
+    let selector = selector.resolve_aliases(&config).unwrap();
+    assert!(matches!(&selector.terms[0], Term::Serial(s) if s == "S123"));
+
+    // Bare values that are no alias are left to match a serial or name
+    let selector: Selector = "desk,Other".parse().unwrap();
+    let selector = selector.resolve_aliases(&config).unwrap();
+    assert!(matches!(&selector.terms[0], Term::Serial(s) if s == "S123"));
+    assert!(matches!(&selector.terms[1], Term::Bare(v) if v == "Other"));
+
+    let unknown: Selector = "alias:laptop".parse().unwrap();
+    assert_eq!(unknown.resolve_aliases(&config).unwrap_err(), "Unknown keyboard alias laptop");
+  }
+}
@@ -0,0 +1,235 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use hid_io_client::capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use hid_io_client::common_capnp::NodeType;
+use hid_io_core::hidio_capnp::{hid_io_server, hidio};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use crate::connection::{AuthLevel, ConnectionError};
+
+pub const DEFAULT_HOST: &str = "localhost";
+pub const DEFAULT_PORT: u16 = 7185;
+pub const DEFAULT_NAME: &str = "HID-IO Keyboard";
+
+/// `[connection]` section of the config, overridden by the matching command line options
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+  pub host: Option<String>,
+  pub port: Option<u16>,
+  /// PEM file with the certificate authorities trusted to sign the hid-io-core certificate
+  pub ca: Option<PathBuf>,
+  /// SHA-256 fingerprint of the hid-io-core certificate, hex with optional colons
+  pub pin: Option<String>,
+  /// Trust any certificate from a remote hid-io-core, as is always done on loopback
+  pub insecure: bool,
+  /// Key file to authenticate with, instead of the path hid-io-core reports on its own machine
+  pub key: Option<PathBuf>,
+  /// Milliseconds to wait for the connection and authentication to complete
+  pub timeout: Option<u64>,
+  /// Name this client registers with in hid-io-core
+  pub name: Option<String>,
+}
+
+/// Where and how to reach hid-io-core
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+  pub host: String,
+  pub port: u16,
+  pub ca: Option<PathBuf>,
+  pub pin: Option<String>,
+  pub insecure: bool,
+  pub key: Option<PathBuf>,
+  pub timeout: Duration,
+  pub name: String,
+}
+
+impl Endpoint {
+  /// Whether the bundled client, which only knows the local default endpoint, can be used
+  pub fn is_default(&self) -> bool {
+    self.host == DEFAULT_HOST
+      && self.port == DEFAULT_PORT
+      && self.ca.is_none()
+      && self.pin.is_none()
+      && self.key.is_none()
+  }
+
+  /// Whether hid-io-core runs on this machine, where its certificate cannot be intercepted
+  fn is_loopback(&self) -> bool {
+    self.host == DEFAULT_HOST || self.host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+  }
+
+  fn tls_config(&self) -> Result<rustls::ClientConfig, ConnectionError> {
+    let setup =
+      |msg: String| ConnectionError::Setup(std::io::Error::new(std::io::ErrorKind::Other, msg));
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let config = match (&self.ca, &self.pin) {
+      (Some(_), Some(_)) => return Err(setup("Use either a CA or a certificate pin".to_string())),
+      (Some(ca), None) => {
+        let pem = std::fs::read(ca).map_err(ConnectionError::Setup)?;
+        let certs = rustls_pemfile::certs(&mut pem.as_slice()).map_err(ConnectionError::Setup)?;
+        let mut roots = rustls::RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(&certs);
+        if added == 0 {
+          return Err(setup(format!("No certificates found in {}", ca.display())));
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+      }
+      (None, Some(pin)) => {
+        let fingerprint =
+          parse_fingerprint(pin).ok_or_else(|| setup(format!("Invalid pin {}", pin)))?;
+        builder
+          .with_custom_certificate_verifier(Arc::new(Pinned(fingerprint)))
+          .with_no_client_auth()
+      }
+      // hid-io-core generates a self-signed certificate, so the bundled client trusts any
+      (None, None) if self.is_loopback() || self.insecure => {
+        builder.with_custom_certificate_verifier(Arc::new(AnyCert)).with_no_client_auth()
+      }
+      (None, None) => {
+        return Err(setup(format!(
+          "{} is not a local host, trust its certificate with --ca or --pin, or pass --insecure",
+          self.host
+        )))
+      }
+    };
+    Ok(config)
+  }
+
+  /// Open a TLS connection to hid-io-core and authenticate at `level`
+  pub async fn connect(
+    &self,
+    level: AuthLevel,
+    node_type: NodeType,
+    serial: String,
+  ) -> Result<(hidio::Client, hid_io_server::Client), ConnectionError> {
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(self.tls_config()?));
+    let domain = match self.host.parse::<IpAddr>() {
+      Ok(ip) => rustls::ServerName::IpAddress(ip),
+      Err(_) => rustls::ServerName::try_from(self.host.as_str()).map_err(|_| {
+        ConnectionError::Setup(std::io::Error::new(
+          std::io::ErrorKind::InvalidInput,
+          format!("Invalid host {}", self.host),
+        ))
+      })?,
+    };
+    let io = |e: std::io::Error| ConnectionError::Connect(e.into());
+    let stream =
+      tokio::net::TcpStream::connect((self.host.as_str(), self.port)).await.map_err(io)?;
+    stream.set_nodelay(true).map_err(io)?;
+    let stream = connector.connect(domain, stream).await.map_err(io)?;
+
+    let (reader, writer) = tokio::io::split(stream);
+    let network = Box::new(twoparty::VatNetwork::new(
+      reader.compat(),
+      writer.compat_write(),
+      rpc_twoparty_capnp::Side::Client,
+      Default::default(),
+    ));
+    let mut rpc_system = RpcSystem::new(network, None);
+    let server: hid_io_server::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    tokio::task::spawn_local(rpc_system);
+
+    let auth = self.authenticate(&server, level, node_type, serial).await?;
+    Ok((auth, server))
+  }
+
+  async fn authenticate(
+    &self,
+    server: &hid_io_server::Client,
+    level: AuthLevel,
+    node_type: NodeType,
+    serial: String,
+  ) -> Result<hidio::Client, ConnectionError> {
+    let id = server.id_request().send().promise.await?.get()?.get_id();
+    let key_resp = server.key_request().send().promise.await?;
+    let key = key_resp.get()?.get_key()?;
+
+    let name = &self.name;
+    // The key files are only readable by users hid-io-core trusts at that level. The reported
+    // path is on the machine hid-io-core runs on, a copy of the key is given with --key otherwise
+    let read_key = |path: &str| {
+      let path = self.key.clone().unwrap_or_else(|| PathBuf::from(path));
+      std::fs::read_to_string(path).map_err(|_| ConnectionError::Auth(level))
+    };
+    macro_rules! request {
+      ($request:expr, $path:expr) => {{
+        let secret = read_key($path)?;
+        let mut request = $request;
+        let mut info = request.get().init_info();
+        info.set_type(node_type);
+        info.set_name(name);
+        info.set_serial(&serial);
+        info.set_id(id);
+        request.get().set_key(&secret);
+        // Wait for the answer, a denial would otherwise only show up on the first request
+        let response = request.send().promise.await.map_err(|e| match e.kind {
+          capnp::ErrorKind::Disconnected => ConnectionError::Connect(e),
+          _ => ConnectionError::Auth(level),
+        })?;
+        response.get()?.get_port()?
+      }};
+    }
+    Ok(match level {
+      AuthLevel::Basic => request!(server.basic_request(), key.get_basic_key_path()?),
+      AuthLevel::Privileged => request!(server.auth_request(), key.get_auth_key_path()?),
+    })
+  }
+}
+
+/// Parse a SHA-256 fingerprint given as hex, optionally separated by colons
+pub fn parse_fingerprint(pin: &str) -> Option<[u8; 32]> {
+  let hex: String = pin.chars().filter(|c| *c != ':').collect();
+  if hex.len() != 64 {
+    return None;
+  }
+  let mut fingerprint = [0; 32];
+  for (i, byte) in fingerprint.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+  }
+  Some(fingerprint)
+}
+
+/// Accepts only the certificate with the pinned SHA-256 fingerprint
+struct Pinned([u8; 32]);
+
+impl rustls::client::ServerCertVerifier for Pinned {
+  fn verify_server_cert(
+    &self,
+    end_entity: &rustls::Certificate,
+    _intermediates: &[rustls::Certificate],
+    _server_name: &rustls::ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+    if Sha256::digest(&end_entity.0).as_slice() == self.0 {
+      Ok(rustls::client::ServerCertVerified::assertion())
+    } else {
+      Err(rustls::Error::General("hid-io-core certificate does not match the pin".to_string()))
+    }
+  }
+}
+
+/// Accepts any certificate, only used for a local hid-io-core or with `--insecure`
+struct AnyCert;
+
+impl rustls::client::ServerCertVerifier for AnyCert {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &rustls::Certificate,
+    _intermediates: &[rustls::Certificate],
+    _server_name: &rustls::ServerName,
+    _scts: &mut dyn Iterator<Item = &[u8]>,
+    _ocsp_response: &[u8],
+    _now: SystemTime,
+  ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+    Ok(rustls::client::ServerCertVerified::assertion())
+  }
+}
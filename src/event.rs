@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use hid_io_client::keyboard_capnp::keyboard::signal::volume::Command as VolumeCommand;
+use tokio::sync::broadcast;
+
+/// Events raised by the keyboard subscription
+#[derive(Clone, Debug)]
+pub enum Event {
+  LayerChanged(u8),
+  Volume(VolumeCommand, u16, Option<String>),
+  HostMacro(u16),
+  KllTrigger(u16),
+  CliOutput(String),
+  Connected,
+  Disconnected,
+}
+
+/// Lowercase name of a volume command, as used in configuration and templates
+pub fn volume_cmd_name(cmd: VolumeCommand) -> &'static str {
+  match cmd {
+    VolumeCommand::Set => "set",
+    VolumeCommand::Inc => "inc",
+    VolumeCommand::Dec => "dec",
+    VolumeCommand::Mute => "mute",
+    VolumeCommand::UnMute => "unmute",
+    VolumeCommand::ToggleMute => "togglemute",
+  }
+}
+
+/// Something to wait for in the event stream
+#[derive(Clone, Debug)]
+pub enum Condition {
+  Layer(u8),
+  /// Any host macro, or a specific index
+  HostMacro(Option<u16>),
+  /// Any volume signal, or a specific command
+  Volume(Option<String>),
+  /// Any CLI output, or output containing the given text
+  Cli(Option<String>),
+  Connected,
+}
+
+impl Condition {
+  pub fn matches(&self, event: &Event) -> bool {
+    match (self, event) {
+      (Condition::Layer(want), Event::LayerChanged(layer)) => want == layer,
+      (Condition::HostMacro(want), Event::HostMacro(index)) => want.is_none_or(|w| w == *index),
+      (Condition::Volume(want), Event::Volume(cmd, _, _)) => {
+        want.as_ref().is_none_or(|w| w == volume_cmd_name(*cmd))
+      }
+      (Condition::Cli(want), Event::CliOutput(output)) => {
+        want.as_ref().is_none_or(|w| output.contains(w.as_str()))
+      }
+      (Condition::Connected, Event::Connected) => true,
+      _ => false,
+    }
+  }
+}
+
+/// Wait until an event on `events` satisfies `condition`
+pub async fn wait_for(
+  events: &mut broadcast::Receiver<(String, Event)>,
+  condition: &Condition,
+  timeout: Duration,
+) -> Result<String, String> {
+  let wait = async {
+    loop {
+      match events.recv().await {
+        Ok((serial, event)) if condition.matches(&event) => return Ok(serial),
+        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+        Err(broadcast::error::RecvError::Closed) => return Err("session closed".to_string()),
+      }
+    }
+  };
+  match tokio::time::timeout(timeout, wait).await {
+    Ok(result) => result,
+    Err(_) => Err(format!("timed out after {:?} waiting for {:?}", timeout, condition)),
+  }
+}
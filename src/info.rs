@@ -0,0 +1,130 @@
+use std::fmt;
+
+use hid_io_client::common_capnp::destination;
+use hid_io_core::hidio_capnp;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct FirmwareInfo {
+  pub hidio_version: String,
+  pub vendor: String,
+  pub device: String,
+  pub device_version: String,
+  pub mcu: String,
+  pub firmware_name: String,
+  pub firmware_version: String,
+}
+
+#[derive(Serialize)]
+pub struct DaemonInfo {
+  pub version: String,
+  pub build_time: String,
+  pub arch: String,
+  pub uptime_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct KeyboardInfo {
+  pub id: u64,
+  #[serde(rename = "type")]
+  pub node_type: String,
+  pub serial: String,
+  pub name: String,
+  /// Last layer recorded by a subscribed session
+  pub layer: Option<u8>,
+  /// Unset when the firmware does not answer info requests
+  pub firmware: Option<FirmwareInfo>,
+  pub daemon: DaemonInfo,
+}
+
+/// Collect what hid-io-core and the keyboard firmware report about a keyboard
+pub async fn gather(
+  hidio_server: &hidio_capnp::hid_io_server::Client,
+  device: destination::Reader<'_>,
+) -> Result<KeyboardInfo, capnp::Error> {
+  let serial = device.get_serial()?.to_string();
+  let node = match device.get_node().which()? {
+    destination::node::Which::Keyboard(n) => hidio_capnp::node::Client { client: n?.client },
+    destination::node::Which::Daemon(_) => {
+      return Err(capnp::Error::failed(format!("{} is not a keyboard node", serial)));
+    }
+  };
+
+  let firmware = match firmware(&node).await {
+    Ok(firmware) => Some(firmware),
+    Err(e) => {
+      eprintln!("Could not query firmware info: {}", e);
+      None
+    }
+  };
+
+  let version_resp = hidio_server.version_request().send().promise.await?;
+  let version = version_resp.get()?.get_version()?;
+  let daemon = DaemonInfo {
+    version: version.get_version()?.to_string(),
+    build_time: version.get_buildtime()?.to_string(),
+    arch: version.get_serverarch()?.to_string(),
+    uptime_secs: crate::util::process_uptime("hid-io-core").map(|d| d.as_secs()),
+  };
+
+  Ok(KeyboardInfo {
+    id: device.get_id(),
+    node_type: crate::device::type_name(&device).to_string(),
+    layer: crate::modules::layer::load_layer(&serial),
+    serial,
+    name: device.get_name()?.to_string(),
+    firmware,
+    daemon,
+  })
+}
+
+async fn firmware(node: &hidio_capnp::node::Client) -> Result<FirmwareInfo, capnp::Error> {
+  let response = node.info_request().send().promise.await?;
+  let info = response.get()?.get_info()?;
+  Ok(FirmwareInfo {
+    hidio_version: format!(
+      "{}.{}.{}",
+      info.get_hidio_major_version(),
+      info.get_hidio_minor_version(),
+      info.get_hidio_patch_version()
+    ),
+    vendor: info.get_device_vendor()?.to_string(),
+    device: info.get_device_name()?.to_string(),
+    device_version: info.get_device_version()?.to_string(),
+    mcu: info.get_device_mcu()?.to_string(),
+    firmware_name: info.get_firmware_name()?.to_string(),
+    firmware_version: info.get_firmware_version()?.to_string(),
+  })
+}
+
+impl fmt::Display for KeyboardInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Keyboard:  {}", self.name)?;
+    writeln!(f, "Serial:    {}", self.serial)?;
+    writeln!(f, "Node id:   {}", self.id)?;
+    writeln!(f, "Type:      {}", self.node_type)?;
+    match self.layer {
+      Some(layer) => writeln!(f, "Layer:     {}", layer)?,
+      None => writeln!(f, "Layer:     unknown")?,
+    }
+    match &self.firmware {
+      Some(fw) => {
+        writeln!(f, "Firmware:  {} {}", fw.firmware_name, fw.firmware_version)?;
+        writeln!(f, "Device:    {} {} ({}, {})", fw.vendor, fw.device, fw.device_version, fw.mcu)?;
+        writeln!(f, "HID-IO:    {}", fw.hidio_version)?;
+      }
+      None => writeln!(f, "Firmware:  unknown")?,
+    }
+    writeln!(
+      f,
+      "Daemon:    hid-io-core {} ({}, built {})",
+      self.daemon.version, self.daemon.arch, self.daemon.build_time
+    )?;
+    match self.daemon.uptime_secs {
+      Some(secs) => {
+        write!(f, "Uptime:    {}h {}m {}s", secs / 3600, secs / 60 % 60, secs % 60)
+      }
+      None => write!(f, "Uptime:    unknown"),
+    }
+  }
+}
@@ -2,9 +2,48 @@ use capnp::capability::Promise;
 use capnp::traits::IntoInternalStructReader;
 use hid_io_client::capnp_rpc;
 use hid_io_core::keyboard_capnp;
+use tokio::sync::mpsc::UnboundedSender;
 
-#[derive(Default)]
-pub struct KeyboardSubscriberImpl {}
+use crate::event::Event;
+
+pub struct KeyboardSubscriberImpl {
+  serial: String,
+  /// Prefix printed messages with the keyboard serial
+  tag: bool,
+  /// Only forward events, without printing them
+  quiet: bool,
+  events: UnboundedSender<(String, Event)>,
+}
+
+impl KeyboardSubscriberImpl {
+  pub fn new(serial: String, tag: bool, events: UnboundedSender<(String, Event)>) -> Self {
+    Self {
+      serial,
+      tag,
+      quiet: false,
+      events,
+    }
+  }
+
+  pub fn quiet(mut self) -> Self {
+    self.quiet = true;
+    self
+  }
+
+  fn print(&self, msg: &str) {
+    // The session logs events as structured journal entries instead
+    if self.quiet || crate::systemd::journal_enabled() {
+      return;
+    }
+    if self.tag {
+      println!("[{}] {}", self.serial, msg);
+    } else {
+      println!("{}", msg);
+    }
+  }
+
+  fn send(&self, event: Event) { self.events.send((self.serial.clone(), event)).ok(); }
+}
 
 impl keyboard_capnp::keyboard::subscriber::Server for KeyboardSubscriberImpl {
   fn update(
@@ -37,20 +76,39 @@ impl keyboard_capnp::keyboard::subscriber::Server for KeyboardSubscriberImpl {
           0 => None,
           _ => Some(app_raw.to_string()),
         };
-        let msg = hid_client_stdout::Messages::Volume(cmd, vol, app_msg);
+        let msg = hid_client_stdout::Messages::Volume(cmd, vol, app_msg.clone());
         let str = String::try_from(msg).unwrap();
-        println!("{}", str);
+        self.print(&str);
         // handle_volume(cmd, vol, app);
+        self.send(Event::Volume(cmd, vol, app_msg));
       }
       hid_io_client::keyboard_capnp::keyboard::signal::data::Which::LayerChanged(l) => {
         let l = l.unwrap();
         let msg = hid_client_stdout::Messages::LayerChanged(l.get_layer());
         let str = String::try_from(msg).unwrap();
-        println!("{}", str);
+        self.print(&str);
+        match u8::try_from(l.get_layer()) {
+          Ok(layer) => self.send(Event::LayerChanged(layer)),
+          Err(_) => eprintln!("Ignoring layer {} out of range", l.get_layer()),
+        }
+      }
+      hid_io_client::keyboard_capnp::keyboard::signal::data::Which::HostMacro(h) => {
+        let h = h.unwrap();
+        self.print(&format!("HostMacro:{}", h.get_index()));
+        self.send(Event::HostMacro(h.get_index()));
+      }
+      hid_io_client::keyboard_capnp::keyboard::signal::data::Which::Kll(k) => {
+        let k = k.unwrap();
+        self.print(&format!("KllTrigger:{}", k.get_index()));
+        self.send(Event::KllTrigger(k.get_index()));
+      }
+      hid_io_client::keyboard_capnp::keyboard::signal::data::Which::Cli(c) => {
+        let output = capnp_rpc::pry!(capnp_rpc::pry!(c).get_output());
+        self.send(Event::CliOutput(output.to_string()));
       }
       #[allow(unreachable_patterns)]
       _ => {
-        println!("Unknown signal");
+        self.print("Unknown signal");
       }
     }
     Promise::ok(())
@@ -0,0 +1,62 @@
+use hid_io_client::common_capnp::destination;
+use serde::Serialize;
+
+/// One keyboard in `list` output, field names are kept stable for scripts
+#[derive(Serialize)]
+pub struct Row {
+  /// Position among the attached keyboards, as taken by `index:N` selectors
+  pub index: usize,
+  pub id: u64,
+  pub serial: String,
+  pub name: String,
+  #[serde(rename = "type")]
+  pub node_type: String,
+}
+
+impl Row {
+  pub fn from_node(index: usize, node: &destination::Reader) -> Self {
+    Self {
+      index,
+      id: node.get_id(),
+      serial: node.get_serial().unwrap_or_default().to_string(),
+      name: node.get_name().unwrap_or_default().to_string(),
+      node_type: crate::device::type_name(node).to_string(),
+    }
+  }
+}
+
+pub fn print_table(rows: &[Row]) {
+  if rows.is_empty() {
+    println!("None");
+    return;
+  }
+  let id_width = rows.iter().map(|r| r.id.to_string().len()).max().unwrap().max(2);
+  let serial_width = rows.iter().map(|r| r.serial.len()).max().unwrap().max(6);
+  println!("{:<5}  {:<id_width$}  {:<4}  {:<serial_width$}  NAME", "INDEX", "ID", "TYPE", "SERIAL");
+  for r in rows {
+    println!(
+      "{:<5}  {:<id_width$}  {:<4}  {:<serial_width$}  {}",
+      r.index, r.id, r.node_type, r.serial, r.name
+    );
+  }
+}
+
+pub fn print_json(rows: &[Row]) {
+  println!("{}", serde_json::to_string_pretty(rows).unwrap());
+}
+
+pub fn print_csv(rows: &[Row]) {
+  // Quote fields containing separators, doubling embedded quotes
+  let field = |s: &str| {
+    if s.contains([',', '"', '\n']) {
+      format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+      s.to_string()
+    }
+  };
+  // The index column comes last so scripts reading columns by position keep working
+  println!("id,serial,name,type,index");
+  for r in rows {
+    println!("{},{},{},{},{}", r.id, field(&r.serial), field(&r.name), r.node_type, r.index);
+  }
+}
@@ -26,227 +26,514 @@ use std::io::Write;
 
 mod args;
 mod commands;
+mod config;
+mod connection;
+mod control;
+mod device;
+mod endpoint;
+mod event;
+mod info;
 mod json;
 mod keysub;
+mod list;
 mod modules;
+mod script;
+mod session;
+mod shell;
+mod shutdown;
+mod systemd;
 mod util;
+mod wait;
 
-use hid_io_client::capnp;
-use hid_io_client::capnp_rpc;
-use hid_io_client::common_capnp::NodeType;
-use hid_io_client::keyboard_capnp;
 use hid_io_client::setup_logging_lite;
-use rand::Rng;
+
+use crate::connection::{AuthLevel, Connection, ConnectionError, ConnectionState, Supervisor};
 
 #[tokio::main]
-pub async fn main() -> Result<(), capnp::Error> {
+pub async fn main() {
   setup_logging_lite().ok();
   let matches = args::cli().get_matches();
-  tokio::task::LocalSet::new().run_until(try_main(matches)).await
+  if let Some(("install-service", sub_matches)) = matches.subcommand() {
+    install_service(sub_matches);
+  }
+  if let Some(("config", sub_matches)) = matches.subcommand() {
+    // check is the only config subcommand
+    let (_, check_matches) = sub_matches.subcommand().unwrap();
+    std::process::exit(if config::check(check_matches) { 0 } else { 1 });
+  }
+  if let Some(name) = matches.subcommand_name().filter(|n| control::FORWARDED.contains(n)) {
+    // A running subscription already holds a session, and acting next to it would duplicate work.
+    // Commands with their own connection options run over their own connection instead.
+    let own_connection = name != "status" && args::overrides_connection(&matches);
+    if !matches.get_flag("no-daemon") && !own_connection {
+      let argv: Vec<String> = std::env::args().skip(1).collect();
+      if let Some(code) = control::forward(&argv, control::forward_timeout(&matches)).await {
+        std::process::exit(code);
+      }
+    }
+    if name == "status" {
+      println!("Not running");
+      std::process::exit(control::EXIT_NOT_RUNNING);
+    }
+  }
+  if let Err(e) = tokio::task::LocalSet::new().run_until(try_main(matches)).await {
+    eprintln!("{}", e);
+    std::process::exit(1);
+  }
 }
 
-async fn try_main(matches: clap::ArgMatches) -> Result<(), capnp::Error> {
-  // Prepare hid-io-core connection
-  let mut hidio_conn = hid_io_client::HidioConnection::new().unwrap();
-  let mut rng = rand::thread_rng();
+/// Write the systemd user unit, optionally enabling it, then exit
+fn install_service(matches: &clap::ArgMatches) -> ! {
+  let unit = matches.get_one::<String>("unit").unwrap();
+  let args: Vec<String> = matches.get_many::<String>("ARGS").unwrap_or_default().cloned().collect();
+  let watchdog = *matches.get_one::<u64>("watchdog").unwrap();
+  let path = match systemd::install_service(unit, &args, watchdog, matches.get_flag("force")) {
+    Ok(path) => path,
+    Err(e) => {
+      eprintln!("Could not write the service unit: {}", e);
+      std::process::exit(1);
+    }
+  };
+  println!("Wrote {}", path.display());
+  if !matches.get_flag("enable") {
+    println!("Start it with: systemctl --user enable --now {}", unit);
+    std::process::exit(0);
+  }
+  let unit_file = format!("{}.service", unit);
+  for argv in [vec!["daemon-reload"], vec!["enable", "--now", unit_file.as_str()]] {
+    let status = std::process::Command::new("systemctl").arg("--user").args(&argv).status();
+    match status {
+      Ok(status) if status.success() => {}
+      Ok(status) => {
+        eprintln!("systemctl --user {} failed: {}", argv.join(" "), status);
+        std::process::exit(1);
+      }
+      Err(e) => {
+        eprintln!("Could not run systemctl: {}", e);
+        std::process::exit(1);
+      }
+    }
+  }
+  println!("Enabled and started {}", unit_file);
+  std::process::exit(0);
+}
 
-  // Serial is used for automatic reconnection if hid-io goes away and comes back
-  let mut serial = "".to_string();
+/// Keyboards whose profile asks for it, or the only keyboard attached
+fn default_selectors(config: &config::Config) -> Vec<device::Selector> {
+  let mut selectors: Vec<_> = config
+    .devices
+    .iter()
+    .filter(|(_, profile)| profile.subscribe)
+    .map(|(serial, _)| device::Selector::serial(serial))
+    .collect();
+  if selectors.is_empty() {
+    selectors.push(device::Selector::auto());
+  }
+  selectors
+}
 
-  loop {
-    // Connect and authenticate with hid-io-core
-    let (hidio_auth, hidio_server) = hidio_conn
-      .connect(
-        hid_io_client::AuthType::Priviledged,
-        NodeType::HidioApi,
-        "HID-IO Keyboard".to_string(),
-        format!("{:x} - pid:{}", rng.gen::<u64>(), std::process::id()),
-        true,
-        std::time::Duration::from_millis(1000),
-      )
-      .await?;
-    let hidio_auth = hidio_auth.expect("Could not authenticate to hid-io-core");
+/// One line summary of the attached keyboards and their layers for `systemctl status`
+fn daemon_status(session: &session::Session) -> String {
+  let keyboards = session.keyboards.status();
+  if keyboards.is_empty() {
+    "Waiting for a matching keyboard".to_string()
+  } else {
+    format!("Attached: {}", keyboards.join(", "))
+  }
+}
 
-    let nodes_resp = {
-      let request = hidio_auth.nodes_request();
-      request.send().promise.await.unwrap()
-    };
-    let nodes = nodes_resp.get()?.get_nodes()?;
+/// Release the subscribed keyboards and exit after SIGINT or SIGTERM
+async fn shut_down(session: &mut session::Session, signal: &str) -> ! {
+  println!("Received {}, shutting down", signal);
+  systemd::notify("STOPPING=1");
+  let ok = session.shutdown().await;
+  std::io::stdout().flush().ok();
+  std::process::exit(match ok {
+    true => shutdown::EXIT_OK,
+    false => shutdown::EXIT_INCOMPLETE,
+  });
+}
 
-    let find_kb = |serial_arg: Option<&String>, name_arg: Option<&String>| -> String {
-      match serial_arg {
-        Some(n) => {
-          println!("Serial specified: {}", n);
-          n.to_owned()
-        }
-        None => {
-          let mut ser: String = "".to_string();
+async fn try_main(matches: clap::ArgMatches) -> Result<(), ConnectionError> {
+  // Prepare hid-io-core connection
+  let mut config = matches.subcommand().map(|(_, m)| config::from_matches(m)).unwrap_or_default();
+  let endpoint = args::endpoint(&matches, &config.connection);
+  let level = args::auth_level(&matches, &config);
+  let mut supervisor = Supervisor::new(endpoint, level, args::retry_policy(&matches))?;
+  let mut signals = None;
+  let notify = matches.subcommand_name() == Some("daemon");
+  if notify {
+    systemd::enable_journal();
+    supervisor.on_state(|state| {
+      let problem = !matches!(state, ConnectionState::Connecting | ConnectionState::Connected);
+      if !systemd::log_connection(&state.to_string(), problem) {
+        println!("{}", state);
+      }
+      if problem {
+        systemd::notify(&format!("STATUS={}", state));
+      }
+    });
+  }
+  let mut control = None;
+  let mut watcher = None;
+  // READY=1 is sent on the first connection only, later ones just update the status
+  let mut ready = false;
+  // Keyboards to subscribe to and whether they come from the profiles, resolved on the first
+  // connection only so a reconnect neither prompts nor gives up
+  let mut subscribed: Option<(Vec<device::Selector>, bool)> = None;
+  if let Some(("subscribe" | "daemon", sub_matches)) = matches.subcommand() {
+    // Integrations follow changes to the config file without dropping the subscriptions
+    watcher = config::watch_path(sub_matches).map(config::Watcher::new);
+    // Only one instance may act on keyboard events, others talk to it over the control socket
+    match control::Control::bind(config.clone(), level) {
+      Ok(bound) => control = Some(bound),
+      Err(e @ control::LockError::Running(_)) => {
+        eprintln!("{}", e);
+        std::process::exit(1);
+      }
+      Err(e) => eprintln!("{}", e),
+    }
+    // Connection changes are part of the event stream of a long running subscription
+    if !notify {
+      supervisor.on_state(|state| println!("{}", state));
+    }
+  }
 
-          let matched: Vec<_>;
-          if name_arg.is_some() && serial_arg.is_some() {
-            matched = nodes
-              .iter()
-              .filter(|n| {
-                n.get_serial().unwrap() == serial && n.get_name().unwrap() == name_arg.unwrap()
-              })
-              .collect();
-          } else if name_arg.is_some() {
-            matched = nodes.iter().filter(|n| n.get_name().unwrap() == name_arg.unwrap()).collect();
-          } else {
-            matched = nodes.iter().filter(|n| n.get_serial().unwrap() == serial).collect();
-          }
-          // First attempt to match serial number
-          if !serial.is_empty() && matched.len() == 1 {
-            let n = matched[0];
-            println!("Re-registering to {}", hid_io_client::format_node(n));
-            ser = matched[0].get_serial().unwrap().to_owned();
-          } else {
-            let keyboards: Vec<_> = nodes
-              .iter()
-              .filter(|n| {
-                n.get_type().unwrap() == NodeType::UsbKeyboard
-                  || n.get_type().unwrap() == NodeType::BleKeyboard
-              })
-              .collect();
+  loop {
+    // Connect and authenticate with hid-io-core, nothing to release yet if a signal arrives
+    let Connection {
+      auth: hidio_auth,
+      server: hidio_server,
+    } = tokio::select! {
+      connection = supervisor.connect() => connection?,
+      signal = shutdown::recv(&mut signals) => {
+        println!("Received {}, exiting", signal);
+        systemd::notify("STOPPING=1");
+        std::process::exit(shutdown::EXIT_OK);
+      }
+    };
 
-            // Next, if serial number is unset and there is only one keyboard, automatically attach
-            if serial.is_empty() && keyboards.len() == 1 {
-              let n = keyboards[0];
-              println!("Registering to {}", hid_io_client::format_node(n));
-              ser = n.get_serial().unwrap().to_owned();
-              // Otherwise display a list of keyboard nodes
-            }
-          }
-          ser
+    // A session that cannot list nodes is retried like a failed connection, with backoff
+    let nodes_resp = match hidio_auth.nodes_request().send().promise.await {
+      Ok(resp) => resp,
+      Err(e) => {
+        supervisor.failed(e.into());
+        continue;
+      }
+    };
+    let nodes = match nodes_resp.get().and_then(|r| r.get_nodes()) {
+      Ok(nodes) => nodes,
+      Err(e) => {
+        supervisor.failed(e.into());
+        continue;
+      }
+    };
+    supervisor.established();
+
+    // Resolve the keyboard a single-keyboard subcommand talks to, listing candidates on failure
+    let select_kb = |sub_matches: &clap::ArgMatches| {
+      let selected =
+        args::selector(sub_matches, &config).and_then(|s| device::select(nodes, &s, true));
+      match selected {
+        Ok(device) => device,
+        Err(e) => {
+          eprintln!("{}", e);
+          std::process::exit(1);
         }
       }
     };
 
     // Handle Args
     match matches.subcommand() {
-      Some(("list", _)) => {
-        let keyboards: Vec<_> = nodes
+      Some(("list", sub_matches)) => {
+        let type_arg = sub_matches.get_one::<String>("type");
+        let name_arg = sub_matches.get_one::<String>("name");
+        // Indexes are taken before filtering, so they match `index:N` selectors
+        let rows: Vec<_> = nodes
           .iter()
-          .filter(|n| {
-            n.get_type().unwrap() == NodeType::UsbKeyboard
-              || n.get_type().unwrap() == NodeType::BleKeyboard
-          })
+          .filter(device::is_keyboard)
+          .enumerate()
+          .filter(|(_, n)| type_arg.is_none_or(|t| device::type_name(n).eq_ignore_ascii_case(t)))
+          .filter(|(_, n)| name_arg.is_none_or(|p| device::glob_match(p, n.get_name().unwrap())))
+          .map(|(i, n)| list::Row::from_node(i, &n))
           .collect();
-        if keyboards.is_empty() {
-          println!("None");
-          std::process::exit(0);
-        }
-        for n in keyboards {
-          // TODO: Fix formatting
-          println!("{}", hid_client_stdout::stdout_from_node(n));
+        match sub_matches.get_one::<String>("format").unwrap().as_str() {
+          "json" => list::print_json(&rows),
+          "csv" => list::print_csv(&rows),
+          _ => list::print_table(&rows),
         }
         std::process::exit(0);
       }
       Some(("exec", sub_matches)) => {
         use crate::commands::Commands;
-        let command: Option<Commands>;
-        let serial_arg = sub_matches.try_get_one::<String>("serial").unwrap();
-        let name_arg = sub_matches.try_get_one::<String>("name").unwrap();
-        serial = find_kb(serial_arg.clone(), name_arg.clone());
-        match sub_matches.subcommand() {
-          Some(("LayerSet", sub_matches1)) => {
-            let layer_arg = sub_matches1.get_one::<String>("LAYER").unwrap();
-            println!("exec LayerSet: {}", layer_arg);
-
-            let layer = layer_arg.parse::<u8>().unwrap();
-            command = Some(Commands::LayerSet(layer));
-          }
-          _ => unreachable!(),
-        }
-        if command.is_some() {
-          let device = nodes.iter().find(|n| {
-            println!("Found: {}", n.get_serial().unwrap());
-            n.get_serial().unwrap() == serial
-          });
-          if device.is_none() {
-            eprintln!("Could not find node: {}", serial);
+        let device = select_kb(sub_matches);
+        let (name, sub_matches1) = sub_matches.subcommand().unwrap();
+        let command = match Commands::try_from((name, sub_matches1)) {
+          Ok(command) => command,
+          Err(e) => {
+            println!("{}", e);
             std::process::exit(1);
           }
-          let device = device.unwrap();
-          // serial = device.get_serial().unwrap().to_string();
-          println!("READY");
+        };
+        println!("exec {}", name);
+        println!("READY");
 
-          let node = match device.get_node().which().unwrap() {
-            hid_io_client::common_capnp::destination::node::Which::Keyboard(n) => n.unwrap(),
-            hid_io_client::common_capnp::destination::node::Which::Daemon(_) => {
-              std::process::exit(1);
-            }
-          };
+        let node = match device.get_node().which().map_err(capnp::Error::from)? {
+          hid_io_client::common_capnp::destination::node::Which::Keyboard(n) => n?,
+          hid_io_client::common_capnp::destination::node::Which::Daemon(_) => {
+            std::process::exit(1);
+          }
+        };
 
-          let node = hid_io_core::hidio_capnp::node::Client {
-            client: node.client,
-          };
-          let mut request;
-          match command.unwrap() {
-            Commands::LayerSet(layer) => {
-              request = node.layer_set_command_request();
-              request.get().set_layer(layer.into());
-              let _callback = request.send().promise.await.unwrap();
-              println!("LayerSet: {} request sent", layer);
-              std::process::exit(0);
-            }
+        let node = hid_io_core::hidio_capnp::node::Client {
+          client: node.client,
+        };
+        let ctx = commands::Context::new(device.get_serial()?.to_string(), &config);
+        match command.run(&node, &ctx).await {
+          Ok(report) => {
+            println!("{}", report);
+            std::process::exit(0);
+          }
+          Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
           }
-        } else {
-          println!("Command does not exist");
-          std::process::exit(1);
         }
       }
-      Some(("subscribe", sub_matches)) => {
-        let serial_arg = sub_matches.try_get_one::<String>("serial").unwrap();
-        let name_arg = sub_matches.try_get_one::<String>("name").unwrap();
-        println!("Calling out to subscribe with {:?}, {:?}", serial_arg, name_arg);
-
-        serial = find_kb(serial_arg.clone(), name_arg.clone());
+      Some(("shell", sub_matches)) => {
+        let device = select_kb(sub_matches);
+        println!("Connected to {}", hid_io_client::format_node(device));
 
-        let device = nodes.iter().find(|n| {
-          println!("Found: {}", n.get_serial().unwrap());
-          n.get_serial().unwrap() == serial
-        });
-        if device.is_none() {
-          eprintln!("Could not find node: {}", serial);
+        if let Err(e) = shell::run(device, &config).await {
+          eprintln!("{}", e);
           std::process::exit(1);
         }
-        let device = device.unwrap();
-        // serial = device.get_serial().unwrap().to_string();
-
-        // Build subscription callback
-        let subscription = capnp_rpc::new_client(keysub::KeyboardSubscriberImpl::default());
+        std::process::exit(0);
+      }
+      Some(("info", sub_matches)) => {
+        let device = select_kb(sub_matches);
+        let info = match info::gather(&hidio_server, device).await {
+          Ok(info) => info,
+          Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          }
+        };
+        match sub_matches.get_one::<String>("format").unwrap().as_str() {
+          "json" => println!("{}", serde_json::to_string_pretty(&info).unwrap()),
+          _ => println!("{}", info),
+        }
+        std::process::exit(0);
+      }
+      Some(("wait-for", sub_matches)) => {
+        let selectors = match args::selectors(sub_matches, &config) {
+          Ok(selectors) => selectors,
+          Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          }
+        };
+        let condition = match args::condition(sub_matches) {
+          Ok(condition) => condition,
+          Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          }
+        };
+        let timeout =
+          std::time::Duration::from_millis(*sub_matches.get_one::<u64>("timeout").unwrap());
 
-        let subscribe_req = {
-          let node = match device.get_node().which().unwrap() {
-            hid_io_client::common_capnp::destination::node::Which::Keyboard(n) => n.unwrap(),
-            hid_io_client::common_capnp::destination::node::Which::Daemon(_) => {
+        match wait::run(&hidio_auth, &selectors, &condition, timeout).await {
+          Ok(serial) => {
+            println!("{}", serial);
+            std::process::exit(0);
+          }
+          Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          }
+        }
+      }
+      Some(("type", sub_matches)) => {
+        let text = sub_matches.get_one::<String>("TEXT").unwrap();
+        let Some(daemon) = modules::unicode::find_daemon(nodes) else {
+          eprintln!("Could not find the hid-io-core daemon node");
+          std::process::exit(1);
+        };
+        if let Err(e) = modules::unicode::type_text(&daemon, text).await {
+          eprintln!("Could not type text: {}", e);
+          std::process::exit(1);
+        }
+        std::process::exit(0);
+      }
+      Some(("run", sub_matches)) => {
+        let path = sub_matches.get_one::<String>("SCRIPT").unwrap();
+        let script = match script::load(path) {
+          Ok(script) => script,
+          Err(e) => {
+            eprintln!("Could not read script {}: {}", path, e);
+            std::process::exit(1);
+          }
+        };
+        let device = select_kb(sub_matches);
+        let ok = script::run(device, path, &script, &config).await;
+        std::process::exit(if ok { 0 } else { 1 });
+      }
+      Some(("subscribe" | "daemon", sub_matches)) => {
+        let all = sub_matches.get_flag("all");
+        let (selectors, from_profiles) = subscribed.get_or_insert_with(|| {
+          let mut selectors = match args::selectors(sub_matches, &config) {
+            Ok(selectors) => selectors,
+            Err(e) => {
+              eprintln!("{}", e);
               std::process::exit(1);
             }
           };
+          let from_profiles = selectors.is_empty() && !all;
+          if from_profiles {
+            selectors = default_selectors(&config);
+          }
+          let names: Vec<String> = selectors.iter().map(|s| s.to_string()).collect();
+          println!("Calling out to subscribe with {:?}", names);
 
-          let mut request = node.subscribe_request();
-          let mut params = request.get();
-          params.set_subscriber(subscription);
+          if !all {
+            if let Err(e) = device::resolve_auto(nodes, &mut selectors) {
+              eprintln!("{}", e);
+              std::process::exit(1);
+            }
+          }
+          (selectors, from_profiles)
+        });
+        // Installed after the keyboard picker so Ctrl-C still interrupts it
+        if signals.is_none() {
+          match shutdown::Signals::new() {
+            Ok(handlers) => signals = Some(handlers),
+            Err(e) => {
+              eprintln!("Could not install signal handlers: {}", e);
+              std::process::exit(1);
+            }
+          }
+        }
+        let devices = device::find_kbs(nodes, selectors, all);
 
-          // Build list of options
-          let options = params.init_options(1);
-          options.get(0).set_type(keyboard_capnp::keyboard::SubscriptionOptionType::Volume);
-          request
+        // Events are only tagged with their keyboard when more than one may be attached
+        let tag = all || selectors.len() > 1 || devices.len() > 1;
+        let daemon = modules::unicode::find_daemon(nodes);
+        let mut session = session::Session::new(config.clone(), tag, daemon);
+        // A hung hid-io-core must not keep a signal from being handled
+        let interrupted = tokio::select! {
+          _ = session.sync(nodes, devices) => None,
+          signal = shutdown::recv(&mut signals) => Some(signal),
         };
-        let _callback = subscribe_req.send().promise.await.unwrap();
+        if let Some(signal) = interrupted {
+          shut_down(&mut session, signal).await;
+        }
+        if session.keyboards.is_empty() {
+          println!("Waiting for a matching keyboard");
+        }
+        if let Some(control) = &control {
+          control.attach(hidio_auth.clone(), session.keyboards.clone());
+        }
 
         println!("READY");
+        let mut status = String::new();
+        // Set when a reload changed the selectors, the keyboards they drop are released next poll
+        let mut reselected = false;
         loop {
-          tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+          if notify {
+            let current = daemon_status(&session);
+            if !ready {
+              systemd::notify(&format!("READY=1\nSTATUS={}", current));
+              ready = true;
+              status = current;
+            } else if current != status {
+              systemd::notify(&format!("STATUS={}", current));
+              status = current;
+            }
+          }
+          tokio::select! {
+            _ = tokio::time::sleep(std::time::Duration::from_millis(1000)) => {}
+            signal = shutdown::recv(&mut signals) => shut_down(&mut session, signal).await,
+          }
+
+          if let Some(reloaded) = watcher.as_mut().and_then(config::Watcher::poll) {
+            if reloaded.connection != config.connection {
+              eprintln!("Connection settings changed, restart to apply them");
+            }
+            if level == AuthLevel::Basic && reloaded.needs_privileged() {
+              eprintln!("The config now needs privileged access, restart to authenticate again");
+            }
+            session.reload(&reloaded);
+            if let Some(control) = &control {
+              control.set_config(reloaded.clone());
+            }
+            // Left alone unless the profiles changed, `auto` may have been resolved at startup
+            let names = |selectors: &[device::Selector]| -> Vec<String> {
+              selectors.iter().map(|s| s.to_string()).collect()
+            };
+            let profiles = default_selectors(&reloaded);
+            if *from_profiles && names(&profiles) != names(&default_selectors(&config)) {
+              *selectors = profiles;
+              reselected = true;
+            }
+            config = reloaded;
+          }
 
           // Check if the server is still alive
           let request = hidio_server.alive_request();
-          if let Err(e) = request.send().promise.await {
-            println!("Dead: {}", e);
+          let alive = tokio::select! {
+            alive = request.send().promise => alive,
+            signal = shutdown::recv(&mut signals) => shut_down(&mut session, signal).await,
+          };
+          if let Err(e) = alive {
             // Break the subscription loop and attempt to reconnect
+            supervisor.lost(&e.into());
             break;
           }
+          systemd::watchdog();
+
+          // Pick up keyboards that were plugged in or removed since the last check
+          let listed = tokio::select! {
+            listed = hidio_auth.nodes_request().send().promise => listed,
+            signal = shutdown::recv(&mut signals) => shut_down(&mut session, signal).await,
+          };
+          let nodes_resp = match listed {
+            Ok(resp) => resp,
+            Err(e) => {
+              supervisor.lost(&e.into());
+              break;
+            }
+          };
+          let interrupted = match nodes_resp.get().and_then(|r| r.get_nodes()) {
+            Ok(nodes) => {
+              if !all {
+                device::pin_auto(nodes, selectors);
+              }
+              let devices = device::find_kbs(nodes, selectors, all);
+              let release = std::mem::take(&mut reselected);
+              let update = async {
+                // Keyboards no longer selected after a reload are released here
+                if release {
+                  session.release_unselected(nodes, &devices).await;
+                }
+                session.sync(nodes, devices).await;
+              };
+              tokio::select! {
+                _ = update => None,
+                signal = shutdown::recv(&mut signals) => Some(signal),
+              }
+            }
+            Err(e) => {
+              eprintln!("Could not list nodes: {}", e);
+              None
+            }
+          };
+          if let Some(signal) = interrupted {
+            shut_down(&mut session, signal).await;
+          }
+        }
+        if let Some(control) = &control {
+          control.detach();
         }
       }
       _ => {
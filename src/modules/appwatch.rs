@@ -0,0 +1,222 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+
+use crate::connection::RetryPolicy;
+use crate::event::Event;
+use crate::modules::hyprland::{self, HyprlandConfig};
+use crate::session::Keyboards;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+  #[default]
+  Hyprland,
+  Sway,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppRule {
+  /// Window class (Hyprland, X11 apps under sway) or app_id (native Wayland apps under sway)
+  pub app: String,
+  pub layer: u8,
+  /// Serials or aliases of the keyboards this rule applies to, every keyboard when empty
+  #[serde(default)]
+  pub keyboards: Vec<String>,
+}
+
+impl AppRule {
+  fn applies_to(&self, serial: &str) -> bool {
+    self.keyboards.is_empty() || self.keyboards.iter().any(|k| k == serial)
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppsConfig {
+  pub backend: Backend,
+  pub rules: Vec<AppRule>,
+}
+
+/// Switches the keyboard layers to follow the focused window
+#[derive(Clone)]
+pub struct AppWatch {
+  config: AppsConfig,
+  /// Layer last reported by each keyboard, by serial
+  layers: Rc<RefCell<HashMap<String, u8>>>,
+  /// Layer each keyboard goes back to once focus leaves a matched app
+  restore: Rc<RefCell<HashMap<String, u8>>>,
+}
+
+impl AppWatch {
+  pub fn new(config: AppsConfig) -> Self {
+    Self {
+      config,
+      layers: Rc::new(RefCell::new(HashMap::new())),
+      restore: Rc::new(RefCell::new(HashMap::new())),
+    }
+  }
+
+  pub fn dispatch(&self, serial: &str, event: &Event) {
+    if let Event::LayerChanged(layer) = event {
+      self.layers.borrow_mut().insert(serial.to_string(), *layer);
+    }
+  }
+
+  /// Watch focus changes for as long as the session runs
+  ///
+  /// The compositor connection goes away whenever it restarts, so it is reconnected with the
+  /// same backoff as the hid-io-core connection.
+  pub async fn watch(&self, keyboards: Keyboards, hyprland_config: &HyprlandConfig) {
+    let policy = RetryPolicy {
+      initial_delay: Duration::from_millis(500),
+      max_retries: None,
+    };
+    let mut rng = rand::thread_rng();
+    let mut attempt = 0;
+    loop {
+      let result = match self.connect(hyprland_config).await {
+        Ok(focus) => {
+          attempt = 0;
+          match focus {
+            Focus::Hyprland(lines) => self.watch_hyprland(lines, &keyboards).await,
+            Focus::Sway(stream) => self.watch_sway(stream, &keyboards).await,
+          }
+        }
+        Err(e) => Err(e),
+      };
+      attempt += 1;
+      let delay = policy.delay(attempt, &mut rng);
+      let reason = match result {
+        Ok(()) => "compositor connection closed".to_string(),
+        Err(e) => e.to_string(),
+      };
+      eprintln!("ERROR: appwatch - {}, reconnecting in {:.1}s", reason, delay.as_secs_f32());
+      tokio::time::sleep(delay).await;
+    }
+  }
+
+  /// Open the focus event stream of the configured compositor
+  async fn connect(&self, hyprland_config: &HyprlandConfig) -> io::Result<Focus> {
+    match self.config.backend {
+      Backend::Hyprland => {
+        let dir = hyprland::socket_dir(hyprland_config)?;
+        Ok(Focus::Hyprland(hyprland::events(&dir).await?))
+      }
+      Backend::Sway => {
+        let path = std::env::var("SWAYSOCK")
+          .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "SWAYSOCK not set"))?;
+        let mut stream = UnixStream::connect(path).await?;
+        sway_send(&mut stream, SWAY_SUBSCRIBE, br#"["window"]"#).await?;
+        Ok(Focus::Sway(stream))
+      }
+    }
+  }
+
+  async fn focused(&self, keyboards: &Keyboards, app: &str) {
+    let mut targets = Vec::new();
+    {
+      let layers = self.layers.borrow();
+      let mut restore = self.restore.borrow_mut();
+      for serial in keyboards.serials() {
+        let rule = self.config.rules.iter().find(|r| r.app == app && r.applies_to(&serial));
+        let target = match rule {
+          Some(rule) => {
+            // Nothing is restored when the layer was never reported, rather than guessing one
+            if let Some(layer) = layers.get(&serial) {
+              restore.entry(serial.clone()).or_insert(*layer);
+            }
+            rule.layer
+          }
+          None => match restore.remove(&serial) {
+            Some(layer) => layer,
+            None => continue,
+          },
+        };
+        if layers.get(&serial) != Some(&target) {
+          targets.push((serial, target));
+        }
+      }
+    }
+    for (serial, layer) in targets {
+      for (serial, e) in keyboards.set_layer(std::slice::from_ref(&serial), layer).await {
+        eprintln!("ERROR: appwatch - could not set layer {} on {}: {}", layer, serial, e);
+      }
+    }
+  }
+
+  /// Follow focus changes until Hyprland closes the event socket
+  async fn watch_hyprland(
+    &self,
+    mut lines: Lines<BufReader<UnixStream>>,
+    keyboards: &Keyboards,
+  ) -> io::Result<()> {
+    while let Some(line) = lines.next_line().await? {
+      // activewindow>>CLASS,TITLE
+      let Some(window) = line.strip_prefix("activewindow>>") else {
+        continue;
+      };
+      let class = window.split(',').next().unwrap_or_default();
+      self.focused(keyboards, class).await;
+    }
+    Ok(())
+  }
+
+  /// Follow focus changes until sway closes the IPC socket
+  async fn watch_sway(&self, mut stream: UnixStream, keyboards: &Keyboards) -> io::Result<()> {
+    loop {
+      let (kind, payload) = sway_recv(&mut stream).await?;
+      if kind != SWAY_EVENT_WINDOW {
+        continue;
+      }
+      let event: serde_json::Value = serde_json::from_slice(&payload)?;
+      if event["change"] != "focus" {
+        continue;
+      }
+      let container = &event["container"];
+      let app = container["app_id"]
+        .as_str()
+        .or_else(|| container["window_properties"]["class"].as_str())
+        .unwrap_or_default();
+      self.focused(keyboards, app).await;
+    }
+  }
+}
+
+/// Focus event stream of a compositor
+enum Focus {
+  Hyprland(Lines<BufReader<UnixStream>>),
+  Sway(UnixStream),
+}
+
+const SWAY_MAGIC: &[u8] = b"i3-ipc";
+const SWAY_SUBSCRIBE: u32 = 2;
+const SWAY_EVENT_WINDOW: u32 = 0x8000_0003;
+
+async fn sway_send(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> io::Result<()> {
+  let mut msg = SWAY_MAGIC.to_vec();
+  msg.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+  msg.extend_from_slice(&kind.to_ne_bytes());
+  msg.extend_from_slice(payload);
+  stream.write_all(&msg).await
+}
+
+async fn sway_recv(stream: &mut UnixStream) -> io::Result<(u32, Vec<u8>)> {
+  let mut header = [0u8; 14];
+  stream.read_exact(&mut header).await?;
+  if &header[..6] != SWAY_MAGIC {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad sway ipc magic"));
+  }
+  let len = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+  let kind = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+  let mut payload = vec![0u8; len];
+  stream.read_exact(&mut payload).await?;
+  Ok((kind, payload))
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use hid_io_core::daemon_capnp;
+use serde::Deserialize;
+
+use crate::event::{volume_cmd_name, Event};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+  LayerEntered,
+  LayerLeft,
+  Volume,
+  HostMacro,
+  Connected,
+  Disconnected,
+  /// The subscription is shutting down on SIGINT or SIGTERM
+  Exit,
+}
+
+/// A rule mapping a keyboard event to a shell command and/or text typed on the host
+///
+/// Each element of `run`, and `text`, is a template; `{serial}`, `{layer}`, `{cmd}`, `{vol}`,
+/// `{app}` and `{index}` are replaced with values from the event before it is used.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+  pub on: Trigger,
+  /// Only fire for the keyboard with this serial
+  pub serial: Option<String>,
+  /// Only fire for this layer (layer-entered/layer-left)
+  pub layer: Option<u8>,
+  /// Only fire for this volume command, e.g. "inc" (volume)
+  pub cmd: Option<String>,
+  /// Only fire for this host macro index (host-macro)
+  pub index: Option<u16>,
+  #[serde(default)]
+  pub run: Vec<String>,
+  /// Unicode text typed through the hid-io-core daemon node
+  pub text: Option<String>,
+  /// Milliseconds before the command is killed
+  #[serde(default = "default_timeout")]
+  pub timeout: u64,
+}
+
+fn default_timeout() -> u64 { 5000 }
+
+impl Hook {
+  fn matches(&self, trigger: Trigger, vars: &[(&str, String)]) -> bool {
+    let var = |name: &str| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| v.as_str());
+    self.on == trigger
+      && self.serial.as_ref().is_none_or(|s| var("serial") == Some(s.as_str()))
+      && self.layer.is_none_or(|l| var("layer") == Some(l.to_string().as_str()))
+      && self.cmd.as_ref().is_none_or(|c| var("cmd") == Some(c.as_str()))
+      && self.index.is_none_or(|i| var("index") == Some(i.to_string().as_str()))
+  }
+}
+
+/// Replace every `{name}` in `template` with its value from `vars`
+///
+/// Done in a single pass, so a value that itself contains `{name}` is not substituted again.
+pub fn render(template: &str, vars: &[(&str, String)]) -> String {
+  let mut out = String::with_capacity(template.len());
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    out.push_str(&rest[..start]);
+    let after = &rest[start + 1..];
+    let value = after.find('}').and_then(|end| {
+      let name = &after[..end];
+      vars.iter().find(|(k, _)| *k == name).map(|(_, v)| (v, end))
+    });
+    match value {
+      Some((v, end)) => {
+        out.push_str(v);
+        rest = &after[end + 1..];
+      }
+      // Not a known placeholder, keep the brace as is
+      None => {
+        out.push('{');
+        rest = after;
+      }
+    }
+  }
+  out.push_str(rest);
+  out
+}
+
+pub struct Hooks {
+  hooks: Vec<Hook>,
+  /// Current layer of each keyboard, by serial
+  layers: HashMap<String, u8>,
+  daemon: Option<daemon_capnp::daemon::Client>,
+}
+
+impl Hooks {
+  pub fn new(hooks: Vec<Hook>, daemon: Option<daemon_capnp::daemon::Client>) -> Self {
+    Self {
+      hooks,
+      layers: HashMap::new(),
+      daemon,
+    }
+  }
+
+  pub fn dispatch(&mut self, serial: &str, event: &Event) {
+    let serial = ("serial", serial.to_string());
+    match event {
+      Event::LayerChanged(layer) => {
+        match self.layers.insert(serial.1.clone(), *layer) {
+          Some(prev) if prev == *layer => return,
+          Some(prev) => {
+            self.fire(Trigger::LayerLeft, &[serial.clone(), ("layer", prev.to_string())]);
+          }
+          None => {}
+        }
+        self.fire(Trigger::LayerEntered, &[serial, ("layer", layer.to_string())]);
+      }
+      Event::Volume(cmd, vol, app) => {
+        self.fire(Trigger::Volume, &[
+          serial,
+          ("cmd", volume_cmd_name(*cmd).to_string()),
+          ("vol", vol.to_string()),
+          ("app", app.clone().unwrap_or_default()),
+        ]);
+      }
+      Event::HostMacro(index) => {
+        self.fire(Trigger::HostMacro, &[serial, ("index", index.to_string())]);
+      }
+      Event::KllTrigger(_) | Event::CliOutput(_) => {}
+      Event::Connected => self.fire(Trigger::Connected, &[serial]),
+      Event::Disconnected => {
+        self.layers.remove(&serial.1);
+        self.fire(Trigger::Disconnected, &[serial]);
+      }
+    }
+  }
+
+  fn fire(&self, trigger: Trigger, vars: &[(&str, String)]) {
+    for hook in self.hooks.iter().filter(|h| h.matches(trigger, vars)) {
+      let argv: Vec<String> = hook.run.iter().map(|a| render(a, vars)).collect();
+      spawn(argv, Duration::from_millis(hook.timeout));
+      if let Some(text) = &hook.text {
+        self.type_text(render(text, vars));
+      }
+    }
+  }
+
+  fn type_text(&self, text: String) {
+    let Some(daemon) = self.daemon.clone() else {
+      eprintln!("ERROR: hook - no hid-io-core daemon node to type with");
+      return;
+    };
+    tokio::task::spawn_local(async move {
+      if let Err(e) = crate::modules::unicode::type_text(&daemon, &text).await {
+        eprintln!("ERROR: hook - could not type text: {}", e);
+      }
+    });
+  }
+}
+
+/// Spawn a command without blocking the subscriber, killing it once `timeout` expires
+pub fn spawn(argv: Vec<String>, timeout: Duration) {
+  tokio::task::spawn_local(run(argv, timeout));
+}
+
+/// Run a command to completion, killing it once `timeout` expires; false if it failed
+pub async fn run(argv: Vec<String>, timeout: Duration) -> bool {
+  let Some((program, args)) = argv.split_first() else {
+    return true;
+  };
+  let mut command = tokio::process::Command::new(program);
+  command.args(args).kill_on_drop(true);
+  let mut child = match command.spawn() {
+    Ok(child) => child,
+    Err(e) => {
+      eprintln!("ERROR: hook {} - {}", program, e);
+      return false;
+    }
+  };
+  match tokio::time::timeout(timeout, child.wait()).await {
+    Ok(Ok(status)) if !status.success() => {
+      eprintln!("ERROR: hook {} - {}", program, status);
+      false
+    }
+    Ok(Err(e)) => {
+      eprintln!("ERROR: hook {} - {}", program, e);
+      false
+    }
+    Err(_) => {
+      eprintln!("ERROR: hook {} - timed out after {:?}", program, timeout);
+      child.kill().await.ok();
+      false
+    }
+    _ => true,
+  }
+}
+
+/// Run the `exit` hooks for a keyboard and wait for them to finish; false if any failed
+pub async fn run_exit(
+  hooks: &[Hook],
+  serial: &str,
+  daemon: Option<&daemon_capnp::daemon::Client>,
+) -> bool {
+  let mut vars = vec![("serial", serial.to_string())];
+  if let Some(layer) = crate::modules::layer::load_layer(serial) {
+    vars.push(("layer", layer.to_string()));
+  }
+  let mut ok = true;
+  for hook in hooks.iter().filter(|h| h.matches(Trigger::Exit, &vars)) {
+    let argv: Vec<String> = hook.run.iter().map(|a| render(a, &vars)).collect();
+    ok &= run(argv, Duration::from_millis(hook.timeout)).await;
+    match (&hook.text, daemon) {
+      (Some(text), Some(daemon)) => {
+        if let Err(e) = crate::modules::unicode::type_text(daemon, &render(text, &vars)).await {
+          eprintln!("ERROR: hook - could not type text: {}", e);
+          ok = false;
+        }
+      }
+      (Some(_), None) => {
+        eprintln!("ERROR: hook - no hid-io-core daemon node to type with");
+        ok = false;
+      }
+      _ => {}
+    }
+  }
+  ok
+}
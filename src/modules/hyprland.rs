@@ -0,0 +1,341 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+
+use crate::connection::RetryPolicy;
+use crate::event::Event;
+use crate::session::Keyboards;
+
+/// Submap Hyprland falls back to when no submap is active
+const RESET: &str = "reset";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Submap {
+  pub layer: u8,
+  pub submap: String,
+  /// Serials or aliases of the keyboards this submap applies to, every keyboard when empty
+  #[serde(default)]
+  pub keyboards: Vec<String>,
+}
+
+impl Submap {
+  fn applies_to(&self, serial: &str) -> bool {
+    self.keyboards.is_empty() || self.keyboards.iter().any(|k| k == serial)
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HyprlandConfig {
+  /// Directory holding .socket.sock and .socket2.sock, detected from the environment if unset
+  pub socket_dir: Option<PathBuf>,
+  pub submaps: Vec<Submap>,
+  /// Set the keyboard layer when Hyprland enters a mapped submap
+  pub follow_submaps: bool,
+}
+
+impl HyprlandConfig {
+  fn submap_for(&self, serial: &str, layer: u8) -> &str {
+    let submap = self.submaps.iter().find(|s| s.layer == layer && s.applies_to(serial));
+    submap.map_or(RESET, |s| s.submap.as_str())
+  }
+
+  fn rule_for(&self, submap: &str) -> Option<&Submap> {
+    self.submaps.iter().find(|s| s.submap == submap)
+  }
+}
+
+/// Locate the IPC socket directory of the running Hyprland instance
+pub fn socket_dir(config: &HyprlandConfig) -> io::Result<PathBuf> {
+  if let Some(dir) = &config.socket_dir {
+    return Ok(dir.clone());
+  }
+  let sig = std::env::var("HYPRLAND_INSTANCE_SIGNATURE")
+    .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "HYPRLAND_INSTANCE_SIGNATURE not set"))?;
+  // Hyprland >= 0.40 keeps its sockets under XDG_RUNTIME_DIR, older versions use /tmp
+  if let Ok(runtime) = std::env::var("XDG_RUNTIME_DIR") {
+    let dir = PathBuf::from(runtime).join("hypr").join(&sig);
+    if dir.exists() {
+      return Ok(dir);
+    }
+  }
+  Ok(PathBuf::from("/tmp/hypr").join(sig))
+}
+
+/// Send a request over the command socket and return the reply
+pub async fn request(dir: &Path, msg: &str) -> io::Result<String> {
+  let mut stream = UnixStream::connect(dir.join(".socket.sock")).await?;
+  stream.write_all(msg.as_bytes()).await?;
+  let mut reply = String::new();
+  stream.read_to_string(&mut reply).await?;
+  Ok(reply)
+}
+
+/// Connect to the event socket, yielding one `EVENT>>DATA` line at a time
+pub async fn events(dir: &Path) -> io::Result<Lines<BufReader<UnixStream>>> {
+  let stream = UnixStream::connect(dir.join(".socket2.sock")).await?;
+  Ok(BufReader::new(stream).lines())
+}
+
+/// Last state seen on either side, used to avoid bouncing changes back and forth
+struct SyncState {
+  /// Layer of each keyboard, by serial
+  layers: HashMap<String, u8>,
+  submap: String,
+}
+
+#[derive(Clone)]
+pub struct Hyprland {
+  config: HyprlandConfig,
+  dir: PathBuf,
+  state: Rc<RefCell<SyncState>>,
+}
+
+impl Hyprland {
+  pub fn new(config: HyprlandConfig) -> io::Result<Self> {
+    let dir = socket_dir(&config)?;
+    Ok(Self {
+      config,
+      dir,
+      state: Rc::new(RefCell::new(SyncState {
+        layers: HashMap::new(),
+        submap: RESET.to_string(),
+      })),
+    })
+  }
+
+  pub fn dispatch(&self, serial: &str, event: &Event) {
+    let Event::LayerChanged(layer) = event else {
+      return;
+    };
+    self.state.borrow_mut().layers.insert(serial.to_string(), *layer);
+    // Keyboards no submap applies to, e.g. a numpad, leave Hyprland alone
+    if !self.config.submaps.iter().any(|s| s.applies_to(serial)) {
+      return;
+    }
+    let submap = self.config.submap_for(serial, *layer).to_string();
+    {
+      let mut state = self.state.borrow_mut();
+      if state.submap == submap {
+        return;
+      }
+      state.submap = submap.clone();
+    }
+    let dir = self.dir.clone();
+    tokio::task::spawn_local(async move {
+      match request(&dir, &format!("dispatch submap {}", submap)).await {
+        Ok(reply) if reply.trim() != "ok" => eprintln!("ERROR: hyprland - {}", reply.trim()),
+        Err(e) => eprintln!("ERROR: hyprland - {}", e),
+        _ => {}
+      }
+    });
+  }
+
+  /// Follow Hyprland submap changes and mirror them onto the layer of the keyboards they apply to
+  ///
+  /// The event socket goes away whenever Hyprland restarts, so it is reconnected with the same
+  /// backoff as the hid-io-core connection.
+  pub async fn follow(&self, keyboards: Keyboards) {
+    let policy = RetryPolicy {
+      initial_delay: Duration::from_millis(500),
+      max_retries: None,
+    };
+    let mut rng = rand::thread_rng();
+    let mut attempt = 0;
+    loop {
+      let result = match events(&self.dir).await {
+        Ok(lines) => {
+          attempt = 0;
+          self.follow_lines(lines, &keyboards).await
+        }
+        Err(e) => Err(e),
+      };
+      attempt += 1;
+      let delay = policy.delay(attempt, &mut rng);
+      let reason = match result {
+        Ok(()) => "event socket closed".to_string(),
+        Err(e) => e.to_string(),
+      };
+      eprintln!("ERROR: hyprland - {}, reconnecting in {:.1}s", reason, delay.as_secs_f32());
+      tokio::time::sleep(delay).await;
+    }
+  }
+
+  /// Mirror submap changes until Hyprland closes the event socket
+  async fn follow_lines(
+    &self,
+    mut lines: Lines<BufReader<UnixStream>>,
+    keyboards: &Keyboards,
+  ) -> io::Result<()> {
+    while let Some(line) = lines.next_line().await? {
+      let Some(submap) = line.strip_prefix("submap>>") else {
+        continue;
+      };
+      // An empty submap name means Hyprland went back to the default bindings
+      let submap = if submap.is_empty() { RESET } else { submap };
+      let Some(rule) = self.config.rule_for(submap) else {
+        continue;
+      };
+      let layer = rule.layer;
+      let targets: Vec<String> = {
+        let mut state = self.state.borrow_mut();
+        state.submap = submap.to_string();
+        let targets: Vec<String> = keyboards
+          .serials()
+          .into_iter()
+          .filter(|serial| rule.applies_to(serial) && state.layers.get(serial) != Some(&layer))
+          .collect();
+        for serial in &targets {
+          state.layers.insert(serial.clone(), layer);
+        }
+        targets
+      };
+      for (serial, e) in keyboards.set_layer(&targets, layer).await {
+        eprintln!("ERROR: hyprland - could not set layer {} on {}: {}", layer, serial, e);
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Mutex;
+
+  use capnp::capability::Promise;
+  use hid_io_client::capnp_rpc;
+  use hid_io_core::{hidio_capnp, keyboard_capnp};
+  use tokio::net::UnixListener;
+
+  use super::*;
+  use crate::session::Keyboard;
+
+  /// Layer set requests received by fake keyboard nodes, by serial
+  type Requests = Rc<RefCell<Vec<(String, u16)>>>;
+
+  /// Keyboard node recording the layer set requests it receives
+  struct FakeNode {
+    serial: String,
+    requests: Requests,
+  }
+
+  impl hidio_capnp::node::Server for FakeNode {
+    fn layer_set_command(
+      &mut self,
+      params: hidio_capnp::node::LayerSetCommandParams,
+      _results: hidio_capnp::node::LayerSetCommandResults,
+    ) -> Promise<(), capnp::Error> {
+      let layer = capnp_rpc::pry!(params.get()).get_layer();
+      self.requests.borrow_mut().push((self.serial.clone(), layer));
+      Promise::ok(())
+    }
+  }
+
+  struct FakeSubscription;
+
+  impl keyboard_capnp::keyboard::subscription::Server for FakeSubscription {}
+
+  /// Keyboards with the given serials, backed by fake nodes sharing `requests`
+  fn fake_keyboards(serials: &[&str], requests: &Requests) -> Keyboards {
+    let keyboards = Keyboards::default();
+    for (id, serial) in serials.iter().enumerate() {
+      keyboards.insert(Keyboard {
+        id: id as u64,
+        serial: serial.to_string(),
+        node: capnp_rpc::new_client(FakeNode {
+          serial: serial.to_string(),
+          requests: requests.clone(),
+        }),
+        subscription: capnp_rpc::new_client(FakeSubscription),
+        layer: None,
+      });
+    }
+    keyboards
+  }
+
+  /// The socket directory is read from the environment, which tests must not change concurrently
+  static ENV: Mutex<()> = Mutex::new(());
+
+  /// Client of a fake Hyprland instance, with the listeners of its two IPC sockets
+  fn fake_instance(name: &str) -> (Hyprland, UnixListener, UnixListener) {
+    let _env = ENV.lock().unwrap();
+    let runtime = std::env::temp_dir().join(format!("hidiokb-{}-{}", name, std::process::id()));
+    let dir = runtime.join("hypr").join("fake");
+    std::fs::remove_dir_all(&runtime).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_var("XDG_RUNTIME_DIR", &runtime);
+    std::env::set_var("HYPRLAND_INSTANCE_SIGNATURE", "fake");
+    (
+      Hyprland::new(config()).unwrap(),
+      UnixListener::bind(dir.join(".socket.sock")).unwrap(),
+      UnixListener::bind(dir.join(".socket2.sock")).unwrap(),
+    )
+  }
+
+  fn config() -> HyprlandConfig {
+    HyprlandConfig {
+      socket_dir: None,
+      submaps: vec![Submap {
+        layer: 1,
+        submap: "nav".to_string(),
+        keyboards: vec!["kb-a".to_string()],
+      }],
+      follow_submaps: true,
+    }
+  }
+
+  #[tokio::test]
+  async fn layer_change_dispatches_submap() {
+    let (hyprland, commands, _events) = fake_instance("dispatch");
+    let local = tokio::task::LocalSet::new();
+    let request = local
+      .run_until(async {
+        // No submap applies to the second keyboard, so only the first one dispatches
+        hyprland.dispatch("kb-b", &Event::LayerChanged(1));
+        hyprland.dispatch("kb-a", &Event::LayerChanged(1));
+        let (mut stream, _) = commands.accept().await.unwrap();
+        let mut buf = [0; 64];
+        let n = stream.read(&mut buf).await.unwrap();
+        stream.write_all(b"ok").await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+      })
+      .await;
+    assert_eq!(request, "dispatch submap nav");
+  }
+
+  #[tokio::test]
+  async fn submap_event_sets_layer_on_matching_keyboards() {
+    let (hyprland, _commands, events) = fake_instance("follow");
+    let local = tokio::task::LocalSet::new();
+    let requests = local
+      .run_until(async {
+        let requests = Requests::default();
+        let keyboards = fake_keyboards(&["kb-a", "kb-b"], &requests);
+        let follower = hyprland.clone();
+        tokio::task::spawn_local(async move { follower.follow(keyboards).await });
+        let (mut stream, _) = events.accept().await.unwrap();
+        stream.write_all(b"workspace>>2\nsubmap>>nav\n").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+          while requests.borrow().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+          }
+        })
+        .await
+        .expect("no layer was set");
+        // Give a request to the other keyboard the chance to show up
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let requests = requests.borrow().clone();
+        requests
+      })
+      .await;
+    assert_eq!(requests, vec![("kb-a".to_string(), 1)]);
+  }
+}
@@ -1,3 +1,7 @@
+use std::path::PathBuf;
+
+use hid_io_core::hidio_capnp;
+
 /// Handle layer event
 /// out: raw string from TerminalOut
 pub fn handle_layer_event(out: &str) -> () {
@@ -5,3 +9,31 @@ pub fn handle_layer_event(out: &str) -> () {
     let layer = splt[1].parse::<u8>().unwrap();
     println!("Layer: {}", layer);
 }
+
+/// Ask the keyboard node to switch to `layer`
+pub async fn set_layer(node: &hidio_capnp::node::Client, layer: u8) -> Result<(), capnp::Error> {
+  let mut request = node.layer_set_command_request();
+  request.get().set_layer(layer.into());
+  request.send().promise.await?;
+  Ok(())
+}
+
+/// File recording the last layer seen for a keyboard, shared between processes
+fn state_path(serial: &str) -> Option<PathBuf> {
+  crate::util::state_dir().map(|dir| dir.join(format!("layer-{}", serial)))
+}
+
+/// Last layer recorded for a keyboard, if any
+pub fn load_layer(serial: &str) -> Option<u8> {
+  std::fs::read_to_string(state_path(serial)?).ok()?.trim().parse().ok()
+}
+
+pub fn save_layer(serial: &str, layer: u8) {
+  let Some(path) = state_path(serial) else {
+    return;
+  };
+  std::fs::create_dir_all(path.parent().unwrap()).ok();
+  if let Err(e) = std::fs::write(&path, layer.to_string()) {
+    eprintln!("ERROR: could not record layer in {} - {}", path.display(), e);
+  }
+}
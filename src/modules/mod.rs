@@ -1,2 +1,7 @@
-pub mod volume;
+pub mod appwatch;
+pub mod hooks;
+pub mod hyprland;
 pub mod layer;
+pub mod snippets;
+pub mod unicode;
+pub mod volume;
@@ -0,0 +1,124 @@
+use std::fmt::Write;
+
+use hid_io_core::daemon_capnp;
+use serde::Deserialize;
+
+use crate::event::Event;
+
+/// Text typed on the host when the keyboard sends a host macro or KLL trigger
+///
+/// `text` may contain `{date}`, `{time}`, `{datetime}`, `{date:FORMAT}` (strftime), `{clipboard}`
+/// and `{env:NAME}`; `{{` and `}}` produce literal braces.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snippet {
+  pub host_macro: Option<u16>,
+  pub kll_trigger: Option<u16>,
+  pub text: String,
+}
+
+impl Snippet {
+  fn matches(&self, event: &Event) -> bool {
+    match event {
+      Event::HostMacro(index) => self.host_macro == Some(*index),
+      Event::KllTrigger(index) => self.kll_trigger == Some(*index),
+      _ => false,
+    }
+  }
+}
+
+pub struct Snippets {
+  snippets: Vec<Snippet>,
+  daemon: Option<daemon_capnp::daemon::Client>,
+}
+
+impl Snippets {
+  pub fn new(snippets: Vec<Snippet>, daemon: Option<daemon_capnp::daemon::Client>) -> Self {
+    Self { snippets, daemon }
+  }
+
+  pub fn dispatch(&self, event: &Event) {
+    for snippet in self.snippets.iter().filter(|s| s.matches(event)) {
+      let Some(daemon) = self.daemon.clone() else {
+        eprintln!("ERROR: snippet - no hid-io-core daemon node to type with");
+        return;
+      };
+      let template = snippet.text.clone();
+      tokio::task::spawn_local(async move {
+        let text = expand(&template).await;
+        if let Err(e) = crate::modules::unicode::type_text(&daemon, &text).await {
+          eprintln!("ERROR: snippet - could not type text: {}", e);
+        }
+      });
+    }
+  }
+}
+
+/// Substitute the placeholders in a snippet template
+pub async fn expand(template: &str) -> String {
+  let mut out = String::new();
+  let mut rest = template;
+  while let Some(pos) = rest.find(['{', '}']) {
+    out.push_str(&rest[..pos]);
+    rest = &rest[pos..];
+    if rest.starts_with("{{") || rest.starts_with("}}") {
+      out.push_str(&rest[..1]);
+      rest = &rest[2..];
+      continue;
+    }
+    let end = match rest.find('}') {
+      Some(end) if rest.starts_with('{') => end,
+      _ => {
+        out.push_str(&rest[..1]);
+        rest = &rest[1..];
+        continue;
+      }
+    };
+    let key = &rest[1..end];
+    match placeholder(key).await {
+      Some(value) => out.push_str(&value),
+      None => out.push_str(&rest[..=end]),
+    }
+    rest = &rest[end + 1..];
+  }
+  out.push_str(rest);
+  out
+}
+
+async fn placeholder(key: &str) -> Option<String> {
+  let now = chrono::Local::now();
+  match key.split_once(':') {
+    Some(("date", format)) => {
+      // An invalid format makes Display fail rather than returning an error up front
+      let mut out = String::new();
+      write!(out, "{}", now.format(format)).ok()?;
+      Some(out)
+    }
+    Some(("env", name)) => Some(std::env::var(name).unwrap_or_default()),
+    None if key == "date" => Some(now.format("%Y-%m-%d").to_string()),
+    None if key == "time" => Some(now.format("%H:%M").to_string()),
+    None if key == "datetime" => Some(now.format("%Y-%m-%d %H:%M").to_string()),
+    None if key == "clipboard" => Some(clipboard().await),
+    _ => None,
+  }
+}
+
+/// Current clipboard contents, from wl-paste on Wayland or xclip on X11
+async fn clipboard() -> String {
+  let output = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+    tokio::process::Command::new("wl-paste").arg("--no-newline").output().await
+  } else {
+    tokio::process::Command::new("xclip").args(["-o", "-selection", "clipboard"]).output().await
+  };
+  match output {
+    Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into_owned(),
+    Ok(output) => {
+      eprintln!("ERROR: snippet - clipboard {}", String::from_utf8_lossy(&output.stderr).trim());
+      String::new()
+    }
+    Err(e) => {
+      eprintln!("ERROR: snippet - clipboard {}", e);
+      String::new()
+    }
+  }
+}
@@ -0,0 +1,23 @@
+use hid_io_client::common_capnp::destination;
+use hid_io_core::daemon_capnp;
+
+/// The hid-io-core daemon node, which can type text on the host
+pub fn find_daemon(
+  nodes: capnp::struct_list::Reader<destination::Owned>,
+) -> Option<daemon_capnp::daemon::Client> {
+  nodes.iter().find_map(|n| match n.get_node().which() {
+    Ok(destination::node::Which::Daemon(d)) => d.ok(),
+    _ => None,
+  })
+}
+
+/// Type `text` on the host through the daemon node
+pub async fn type_text(
+  daemon: &daemon_capnp::daemon::Client,
+  text: &str,
+) -> Result<(), capnp::Error> {
+  let mut request = daemon.unicode_string_request();
+  request.get().set_string(text);
+  request.send().promise.await?;
+  Ok(())
+}
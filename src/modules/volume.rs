@@ -77,3 +77,30 @@ pub fn handle_volume(
     }
   }
 }
+
+/// How volume events from a keyboard are applied to the host audio
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioRules {
+  /// Apply volume events through PulseAudio
+  pub enabled: bool,
+  /// Application whose streams are changed when the keyboard does not name one
+  pub app: Option<String>,
+  /// Volume step used in place of the one sent by the keyboard
+  pub step: Option<u16>,
+}
+
+impl AudioRules {
+  pub fn dispatch(&self, event: &crate::event::Event) {
+    let crate::event::Event::Volume(cmd, vol, app) = event else {
+      return;
+    };
+    if !self.enabled {
+      return;
+    }
+    let (cmd, vol) = (*cmd, self.step.unwrap_or(*vol));
+    let app = app.clone().or_else(|| self.app.clone());
+    // pactl is run synchronously, keep it off the event loop
+    tokio::task::spawn_blocking(move || handle_volume(cmd, vol, app.as_deref()));
+  }
+}
@@ -0,0 +1,94 @@
+use std::io::Read;
+use std::time::Duration;
+
+use hid_io_client::common_capnp::destination;
+use hid_io_core::hidio_capnp;
+use tokio::sync::broadcast;
+
+use crate::commands::{Commands, Context};
+use crate::config::Config;
+use crate::event::Event;
+use crate::session::Session;
+
+/// Read a script from a file, or from stdin when `path` is `-`
+pub fn load(path: &str) -> std::io::Result<String> {
+  let mut script = String::new();
+  if path == "-" {
+    std::io::stdin().read_to_string(&mut script)?;
+  } else {
+    script = std::fs::read_to_string(path)?;
+  }
+  Ok(script)
+}
+
+/// Run each line of `script` in turn, stopping at the first failure
+///
+/// Blank lines and lines starting with `#` are skipped. Returns false if a line failed.
+pub async fn run(
+  device: destination::Reader<'_>,
+  name: &str,
+  script: &str,
+  config: &Config,
+) -> bool {
+  let node = match device.get_node().which() {
+    Ok(destination::node::Which::Keyboard(Ok(n))) => hidio_capnp::node::Client { client: n.client },
+    _ => {
+      eprintln!("{}: not a keyboard node", name);
+      return false;
+    }
+  };
+
+  // Subscribe up front so `wait` lines can see events caused by the lines before them
+  let mut session = Session::new(Config::default(), false, None);
+  if let Err(e) = session.attach(device).await {
+    eprintln!("{}: could not subscribe: {}", name, e);
+    return false;
+  }
+  let mut events = session.listen();
+  let ctx = Context::new(device.get_serial().unwrap_or_default().to_string(), config);
+
+  for (lineno, line) in script.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    if let Err(e) = run_line(&node, &ctx, &mut events, line).await {
+      eprintln!("{}:{}: {}", name, lineno + 1, e);
+      return false;
+    }
+  }
+  true
+}
+
+async fn run_line(
+  node: &hidio_capnp::node::Client,
+  ctx: &Context,
+  events: &mut broadcast::Receiver<(String, Event)>,
+  line: &str,
+) -> Result<(), String> {
+  let words = shlex::split(line).ok_or_else(|| "unbalanced quotes".to_string())?;
+  let matches = crate::args::script().try_get_matches_from(words).map_err(|e| e.to_string())?;
+  match matches.subcommand() {
+    Some(("delay", sub_matches)) => {
+      let ms = *sub_matches.get_one::<u64>("MS").unwrap();
+      tokio::time::sleep(Duration::from_millis(ms)).await;
+    }
+    Some(("wait", sub_matches)) => {
+      let condition = crate::args::condition(sub_matches)?;
+      let timeout = Duration::from_millis(*sub_matches.get_one::<u64>("timeout").unwrap());
+      crate::event::wait_for(events, &condition, timeout).await?;
+    }
+    Some((name, sub_matches)) => {
+      let command = Commands::try_from((name, sub_matches))?;
+      // Only events raised from here on count towards a following `wait`
+      while !matches!(
+        events.try_recv(),
+        Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed)
+      ) {}
+      let report = command.run(node, ctx).await.map_err(|e| e.to_string())?;
+      println!("{}", report);
+    }
+    None => {}
+  }
+  Ok(())
+}
@@ -0,0 +1,437 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use hid_io_client::capnp_rpc;
+use hid_io_client::common_capnp::destination;
+use hid_io_core::{daemon_capnp, hidio_capnp, keyboard_capnp};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+use crate::event::Event;
+use crate::keysub::KeyboardSubscriberImpl;
+use crate::modules;
+use crate::modules::volume::AudioRules;
+
+/// A keyboard node with an active subscription
+pub struct Keyboard {
+  pub id: u64,
+  pub serial: String,
+  pub node: hidio_capnp::node::Client,
+  pub subscription: keyboard_capnp::keyboard::subscription::Client,
+  /// Layer last reported by the keyboard, `None` until it reports one
+  pub layer: Option<u8>,
+}
+
+/// Keyboards attached to the session, shared with the integrations that drive them
+#[derive(Clone, Default)]
+pub struct Keyboards(Rc<RefCell<Vec<Keyboard>>>);
+
+impl Keyboards {
+  pub fn is_empty(&self) -> bool { self.0.borrow().is_empty() }
+
+  fn contains(&self, id: u64) -> bool { self.0.borrow().iter().any(|k| k.id == id) }
+
+  /// One line per keyboard with its serial and last known layer
+  pub fn status(&self) -> Vec<String> {
+    self
+      .0
+      .borrow()
+      .iter()
+      .map(|k| match k.layer {
+        Some(layer) => format!("{} on layer {}", k.serial, layer),
+        None => k.serial.clone(),
+      })
+      .collect()
+  }
+
+  /// Add a keyboard without subscribing to it, for tests driving the integrations
+  #[cfg(test)]
+  pub fn insert(&self, keyboard: Keyboard) { self.0.borrow_mut().push(keyboard); }
+
+  pub fn serials(&self) -> Vec<String> {
+    self.0.borrow().iter().map(|k| k.serial.clone()).collect()
+  }
+
+  /// Layer last reported by an attached keyboard
+  pub fn layer(&self, serial: &str) -> Option<u8> {
+    self.0.borrow().iter().find(|k| k.serial == serial)?.layer
+  }
+
+  /// Record a layer reported by a keyboard, returning whether it changed
+  fn record_layer(&self, serial: &str, layer: u8) -> bool {
+    let mut keyboards = self.0.borrow_mut();
+    match keyboards.iter_mut().find(|k| k.serial == serial) {
+      Some(keyboard) if keyboard.layer != Some(layer) => {
+        keyboard.layer = Some(layer);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Set `layer` on the attached keyboards among `serials`
+  ///
+  /// A failure on one keyboard does not stop the others, the failures are returned by serial.
+  pub async fn set_layer(&self, serials: &[String], layer: u8) -> Vec<(String, capnp::Error)> {
+    let nodes: Vec<_> = self
+      .0
+      .borrow()
+      .iter()
+      .filter(|k| serials.contains(&k.serial))
+      .map(|k| (k.serial.clone(), k.node.clone()))
+      .collect();
+    let mut failed = Vec::new();
+    for (serial, node) in nodes {
+      if let Err(e) = modules::layer::set_layer(&node, layer).await {
+        failed.push((serial, e));
+      }
+    }
+    failed
+  }
+}
+
+/// Integrations set up from the config, replaced as a whole when it is reloaded
+struct Integrations {
+  hooks: modules::hooks::Hooks,
+  audio: HashMap<String, AudioRules>,
+  snippets: modules::snippets::Snippets,
+  hyprland: Option<modules::hyprland::Hyprland>,
+  appwatch: Option<modules::appwatch::AppWatch>,
+  /// Background tasks of the integrations, aborted when they are replaced
+  tasks: Vec<JoinHandle<()>>,
+}
+
+impl Integrations {
+  fn new(
+    config: &Config,
+    keyboards: &Keyboards,
+    daemon: Option<daemon_capnp::daemon::Client>,
+  ) -> Self {
+    let mut tasks = Vec::new();
+    let audio = config
+      .devices
+      .iter()
+      .filter_map(|(serial, profile)| Some((serial.clone(), profile.audio.clone()?)))
+      .collect();
+
+    // Rules name keyboards by serial or alias, the integrations only know serials
+    let mut hyprland_config = config.hyprland.clone();
+    for submap in hyprland_config.iter_mut().flat_map(|h| h.submaps.iter_mut()) {
+      submap.keyboards = config.resolve_keyboards(&submap.keyboards);
+    }
+    let mut apps_config = config.apps.clone();
+    for rule in apps_config.iter_mut().flat_map(|a| a.rules.iter_mut()) {
+      rule.keyboards = config.resolve_keyboards(&rule.keyboards);
+    }
+
+    let hyprland = match hyprland_config.clone() {
+      Some(hyprland_config) => match modules::hyprland::Hyprland::new(hyprland_config.clone()) {
+        Ok(hyprland) => {
+          if hyprland_config.follow_submaps {
+            let follower = hyprland.clone();
+            let keyboards = keyboards.clone();
+            tasks.push(tokio::task::spawn_local(async move { follower.follow(keyboards).await }));
+          }
+          Some(hyprland)
+        }
+        Err(e) => {
+          eprintln!("Could not connect to Hyprland: {}", e);
+          None
+        }
+      },
+      None => None,
+    };
+
+    let appwatch = apps_config.map(|apps_config| {
+      let appwatch = modules::appwatch::AppWatch::new(apps_config);
+      let watcher = appwatch.clone();
+      let keyboards = keyboards.clone();
+      let hyprland_config = hyprland_config.unwrap_or_default();
+      tasks.push(tokio::task::spawn_local(async move {
+        watcher.watch(keyboards, &hyprland_config).await
+      }));
+      appwatch
+    });
+
+    Self {
+      hooks: modules::hooks::Hooks::new(config.all_hooks(), daemon.clone()),
+      audio,
+      snippets: modules::snippets::Snippets::new(config.snippets.clone(), daemon),
+      hyprland,
+      appwatch,
+      tasks,
+    }
+  }
+
+  fn dispatch(&mut self, serial: &str, event: &Event) {
+    self.hooks.dispatch(serial, event);
+    if let Some(audio) = self.audio.get(serial) {
+      audio.dispatch(event);
+    }
+    self.snippets.dispatch(event);
+    if let Some(hyprland) = &self.hyprland {
+      hyprland.dispatch(serial, event);
+    }
+    if let Some(appwatch) = &self.appwatch {
+      appwatch.dispatch(serial, event);
+    }
+  }
+}
+
+impl Drop for Integrations {
+  fn drop(&mut self) { self.tasks.iter().for_each(|t| t.abort()); }
+}
+
+/// Subscriptions to one or more keyboards and the integrations fed by their events
+pub struct Session {
+  pub keyboards: Keyboards,
+  events: mpsc::UnboundedSender<(String, Event)>,
+  /// Copy of every dispatched event, for callers waiting on the event stream
+  tap: broadcast::Sender<(String, Event)>,
+  /// Task dispatching events to the integrations, aborted when the session goes away
+  dispatcher: JoinHandle<()>,
+  integrations: Rc<RefCell<Integrations>>,
+  /// Prefix printed events with the serial of the keyboard that raised them
+  tag: bool,
+  /// Print nothing on stdout, the caller reports the outcome itself
+  quiet: bool,
+  /// Hooks run at shutdown, and the layers to restore then, by serial
+  exit_hooks: Vec<modules::hooks::Hook>,
+  exit_layers: HashMap<String, u8>,
+  daemon: Option<daemon_capnp::daemon::Client>,
+}
+
+impl Session {
+  pub fn new(config: Config, tag: bool, daemon: Option<daemon_capnp::daemon::Client>) -> Self {
+    let keyboards = Keyboards::default();
+    let integrations =
+      Rc::new(RefCell::new(Integrations::new(&config, &keyboards, daemon.clone())));
+
+    // Events are dispatched outside of the subscription callback
+    let (events, mut events_rx) = mpsc::unbounded_channel::<(String, Event)>();
+    let (tap, _) = broadcast::channel(64);
+    let dispatch_tap = tap.clone();
+    let dispatch_integrations = integrations.clone();
+    let dispatch_keyboards = keyboards.clone();
+    let dispatcher = tokio::task::spawn_local(async move {
+      while let Some((serial, event)) = events_rx.recv().await {
+        dispatch_tap.send((serial.clone(), event.clone())).ok();
+        crate::systemd::log_event(&serial, &event);
+        // The state file is only written when the layer actually changed
+        match event {
+          Event::LayerChanged(layer) if dispatch_keyboards.record_layer(&serial, layer) => {
+            modules::layer::save_layer(&serial, layer);
+          }
+          _ => {}
+        }
+        dispatch_integrations.borrow_mut().dispatch(&serial, &event);
+      }
+    });
+
+    let mut session = Self {
+      keyboards,
+      events,
+      tap,
+      dispatcher,
+      integrations,
+      tag,
+      quiet: false,
+      exit_hooks: Vec::new(),
+      exit_layers: HashMap::new(),
+      daemon,
+    };
+    session.set_exit_rules(&config);
+    session
+  }
+
+  /// Keep stdout for the caller, e.g. `wait-for` whose output is captured by scripts
+  pub fn quiet(&mut self) { self.quiet = true; }
+
+  fn set_exit_rules(&mut self, config: &Config) {
+    self.exit_hooks =
+      config.all_hooks().into_iter().filter(|h| h.on == modules::hooks::Trigger::Exit).collect();
+    self.exit_layers = config
+      .devices
+      .iter()
+      .filter_map(|(serial, profile)| Some((serial.clone(), profile.exit_layer?)))
+      .collect();
+  }
+
+  /// Switch to a changed config, keeping the keyboard subscriptions
+  pub fn reload(&mut self, config: &Config) {
+    let integrations = Integrations::new(config, &self.keyboards, self.daemon.clone());
+    // The old integrations are dropped here, which stops their background tasks
+    *self.integrations.borrow_mut() = integrations;
+    self.set_exit_rules(config);
+  }
+
+  /// Receive every event dispatched from now on
+  pub fn listen(&self) -> broadcast::Receiver<(String, Event)> { self.tap.subscribe() }
+
+  /// Attach keyboards among `devices` that appeared and detach those gone from `nodes`
+  ///
+  /// Keyboards still in `nodes` stay attached even when `devices` no longer has them, as what an
+  /// `index:N` selector matches shifts when another keyboard is plugged in.
+  pub async fn sync(
+    &mut self,
+    nodes: capnp::struct_list::Reader<'_, destination::Owned>,
+    devices: Vec<destination::Reader<'_>>,
+  ) {
+    let gone: Vec<u64> = self
+      .keyboards
+      .0
+      .borrow()
+      .iter()
+      .filter(|k| !nodes.iter().any(|n| n.get_id() == k.id))
+      .map(|k| k.id)
+      .collect();
+    for id in gone {
+      self.detach(id);
+    }
+    for device in devices {
+      if self.keyboards.contains(device.get_id()) {
+        continue;
+      }
+      if !self.quiet {
+        println!("Registering to {}", hid_io_client::format_node(device));
+      }
+      if let Err(e) = self.attach(device).await {
+        eprintln!("Could not subscribe to {}: {}", hid_io_client::format_node(device), e);
+      }
+    }
+  }
+
+  /// Release the keyboards still in `nodes` that are not among `devices`, as on shutdown
+  ///
+  /// Called after the selectors changed on reload.
+  pub async fn release_unselected(
+    &mut self,
+    nodes: capnp::struct_list::Reader<'_, destination::Owned>,
+    devices: &[destination::Reader<'_>],
+  ) {
+    let unselected: Vec<u64> = self
+      .keyboards
+      .0
+      .borrow()
+      .iter()
+      .filter(|k| !devices.iter().any(|d| d.get_id() == k.id))
+      .filter(|k| nodes.iter().any(|n| n.get_id() == k.id))
+      .map(|k| k.id)
+      .collect();
+    self.release(&unselected).await;
+  }
+
+  /// Subscribe to a keyboard node and add it to the session
+  pub async fn attach(&mut self, device: destination::Reader<'_>) -> Result<(), capnp::Error> {
+    let id = device.get_id();
+    let serial = device.get_serial()?.to_string();
+    let node = match device.get_node().which()? {
+      destination::node::Which::Keyboard(n) => n?,
+      destination::node::Which::Daemon(_) => {
+        return Err(capnp::Error::failed(format!("{} is not a keyboard node", serial)));
+      }
+    };
+
+    // Build subscription callback
+    let mut subscriber =
+      KeyboardSubscriberImpl::new(serial.clone(), self.tag, self.events.clone());
+    if self.quiet {
+      subscriber = subscriber.quiet();
+    }
+    let mut request = node.subscribe_request();
+    let mut params = request.get();
+    params.set_subscriber(capnp_rpc::new_client(subscriber));
+
+    // Build list of options, CLI output is what `wait cli` and `wait-for cli` match on
+    use keyboard_capnp::keyboard::SubscriptionOptionType;
+    let mut options = params.init_options(4);
+    options.reborrow().get(0).set_type(SubscriptionOptionType::Volume);
+    options.reborrow().get(1).set_type(SubscriptionOptionType::HostMacro);
+    options.reborrow().get(2).set_type(SubscriptionOptionType::KllTrigger);
+    options.get(3).set_type(SubscriptionOptionType::CliOutput);
+
+    let response = request.send().promise.await?;
+    let subscription = response.get()?.get_subscription()?;
+    self.keyboards.0.borrow_mut().push(Keyboard {
+      id,
+      serial: serial.clone(),
+      node: hidio_capnp::node::Client {
+        client: node.client,
+      },
+      subscription,
+      layer: None,
+    });
+    if !self.quiet && !crate::systemd::journal_enabled() {
+      println!("Connected: {}", serial);
+    }
+    self.events.send((serial, Event::Connected)).ok();
+    Ok(())
+  }
+
+  /// Drop a keyboard that is no longer attached to hid-io-core
+  pub fn detach(&mut self, id: u64) {
+    let mut keyboards = self.keyboards.0.borrow_mut();
+    let Some(pos) = keyboards.iter().position(|k| k.id == id) else {
+      return;
+    };
+    let keyboard = keyboards.remove(pos);
+    if !self.quiet && !crate::systemd::journal_enabled() {
+      println!("Disconnected: {}", keyboard.serial);
+    }
+    self.events.send((keyboard.serial, Event::Disconnected)).ok();
+  }
+
+  /// Release every keyboard: restore its exit layer, run the exit hooks and unsubscribe
+  ///
+  /// Every keyboard is released even when a step fails; returns false if any did.
+  pub async fn shutdown(&mut self) -> bool {
+    let keyboards = std::mem::take(&mut *self.keyboards.0.borrow_mut());
+    self.release_keyboards(keyboards).await
+  }
+
+  /// Release the keyboards with the given node ids, as `shutdown` does
+  async fn release(&mut self, ids: &[u64]) {
+    let keyboards = {
+      let mut attached = self.keyboards.0.borrow_mut();
+      let (released, kept): (Vec<_>, Vec<_>) =
+        std::mem::take(&mut *attached).into_iter().partition(|k| ids.contains(&k.id));
+      *attached = kept;
+      released
+    };
+    self.release_keyboards(keyboards).await;
+  }
+
+  async fn release_keyboards(&self, keyboards: Vec<Keyboard>) -> bool {
+    let mut ok = true;
+    for keyboard in keyboards {
+      if let Some(layer) = self.exit_layers.get(&keyboard.serial) {
+        if let Err(e) = modules::layer::set_layer(&keyboard.node, *layer).await {
+          eprintln!("Could not restore layer {} on {}: {}", layer, keyboard.serial, e);
+          ok = false;
+        }
+      }
+      ok &=
+        modules::hooks::run_exit(&self.exit_hooks, &keyboard.serial, self.daemon.as_ref()).await;
+
+      let client = keyboard_capnp::keyboard::Client {
+        client: keyboard.node.client.clone(),
+      };
+      let mut request = client.unsubscribe_request();
+      request.get().set_subscription(keyboard.subscription);
+      match request.send().promise.await {
+        Ok(_) => println!("Unsubscribed: {}", keyboard.serial),
+        Err(e) => {
+          eprintln!("Could not unsubscribe from {}: {}", keyboard.serial, e);
+          ok = false;
+        }
+      }
+    }
+    ok
+  }
+}
+
+impl Drop for Session {
+  fn drop(&mut self) { self.dispatcher.abort(); }
+}
@@ -0,0 +1,124 @@
+use hid_io_client::common_capnp::destination;
+use hid_io_core::hidio_capnp;
+use rustyline::error::ReadlineError;
+use tokio::sync::mpsc;
+
+use crate::commands::{Commands, Context};
+use crate::config::Config;
+use crate::session::Session;
+
+const PROMPT: &str = "hidiokb> ";
+
+/// Read lines on a dedicated thread, since the line editor blocks
+///
+/// A line is only read after a request arrives on the returned sender, so the prompt is not
+/// drawn while a command is still printing its output. `None` is sent on end of input.
+fn spawn_reader() -> (std::sync::mpsc::Sender<()>, mpsc::UnboundedReceiver<Option<String>>) {
+  let (ready_tx, ready_rx) = std::sync::mpsc::channel::<()>();
+  let (line_tx, line_rx) = mpsc::unbounded_channel();
+  std::thread::spawn(move || {
+    let mut editor = match rustyline::DefaultEditor::new() {
+      Ok(editor) => editor,
+      Err(e) => {
+        eprintln!("Could not start line editor: {}", e);
+        line_tx.send(None).ok();
+        return;
+      }
+    };
+    let history = crate::util::state_dir().map(|dir| dir.join("history"));
+    if let Some(history) = &history {
+      editor.load_history(history).ok();
+    }
+    while ready_rx.recv().is_ok() {
+      let line = match editor.readline(PROMPT) {
+        Ok(line) => {
+          if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str()).ok();
+            if let Some(history) = &history {
+              std::fs::create_dir_all(history.parent().unwrap()).ok();
+              editor.save_history(history).ok();
+            }
+          }
+          Some(line)
+        }
+        // Ctrl-C abandons the current line only
+        Err(ReadlineError::Interrupted) => Some(String::new()),
+        Err(_) => None,
+      };
+      let eof = line.is_none();
+      if line_tx.send(line).is_err() || eof {
+        break;
+      }
+    }
+  });
+  (ready_tx, line_rx)
+}
+
+/// Interactive shell over a single authenticated hid-io-core session
+pub async fn run(device: destination::Reader<'_>, config: &Config) -> Result<(), capnp::Error> {
+  let node = match device.get_node().which()? {
+    destination::node::Which::Keyboard(n) => hidio_capnp::node::Client { client: n?.client },
+    destination::node::Which::Daemon(_) => {
+      return Err(capnp::Error::failed("Not a keyboard node".to_string()));
+    }
+  };
+  let ctx = Context::new(device.get_serial()?.to_string(), config);
+  // Subscription used to print keyboard events while watching
+  let mut watch: Option<Session> = None;
+
+  let (ready, mut lines) = spawn_reader();
+  loop {
+    if ready.send(()).is_err() {
+      break;
+    }
+    let Some(Some(line)) = lines.recv().await else {
+      break;
+    };
+    let words = match shlex::split(&line) {
+      Some(words) if words.is_empty() => continue,
+      Some(words) => words,
+      None => {
+        eprintln!("Unbalanced quotes");
+        continue;
+      }
+    };
+    let matches = match crate::args::repl().try_get_matches_from(words) {
+      Ok(matches) => matches,
+      Err(e) => {
+        e.print().ok();
+        continue;
+      }
+    };
+    match matches.subcommand() {
+      Some(("quit", _)) => break,
+      Some(("watch", sub_matches)) => {
+        let on = match sub_matches.get_one::<String>("STATE").map(String::as_str) {
+          Some(state) => state == "on",
+          None => watch.is_none(),
+        };
+        if on && watch.is_none() {
+          let mut session = Session::new(Config::default(), false, None);
+          match session.attach(device).await {
+            Ok(()) => {
+              watch = Some(session);
+              println!("Watching events");
+            }
+            Err(e) => eprintln!("Could not watch events: {}", e),
+          }
+        } else if !on {
+          watch = None;
+          println!("Stopped watching events");
+        }
+      }
+      Some((name, sub_matches)) => match Commands::try_from((name, sub_matches)) {
+        Ok(command) => match command.run(&node, &ctx).await {
+          Ok(report) => println!("{}", report),
+          Err(e) => eprintln!("{}", e),
+        },
+        Err(e) => eprintln!("{}", e),
+      },
+      None => {}
+    }
+  }
+  Ok(())
+}
@@ -0,0 +1,40 @@
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Exit status after a clean shutdown on SIGINT or SIGTERM
+pub const EXIT_OK: i32 = 0;
+/// Exit status when restoring a layer, an exit hook or unsubscribing failed during shutdown
+pub const EXIT_INCOMPLETE: i32 = 1;
+
+/// SIGINT and SIGTERM handlers, installed once so no signal is lost while reconnecting
+///
+/// Once installed the signals no longer terminate the process, so every wait in a long running
+/// command has to select on `recv`.
+pub struct Signals {
+  interrupt: Signal,
+  terminate: Signal,
+}
+
+impl Signals {
+  pub fn new() -> std::io::Result<Self> {
+    Ok(Self {
+      interrupt: signal(SignalKind::interrupt())?,
+      terminate: signal(SignalKind::terminate())?,
+    })
+  }
+
+  /// Wait for the next signal, returning its name
+  pub async fn recv(&mut self) -> &'static str {
+    tokio::select! {
+      _ = self.interrupt.recv() => "SIGINT",
+      _ = self.terminate.recv() => "SIGTERM",
+    }
+  }
+}
+
+/// Wait for a signal when handlers are installed, forever otherwise
+pub async fn recv(signals: &mut Option<Signals>) -> &'static str {
+  match signals {
+    Some(signals) => signals.recv().await,
+    None => std::future::pending().await,
+  }
+}
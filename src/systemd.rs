@@ -0,0 +1,181 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::event::{volume_cmd_name, Event};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Set in daemon mode when stdout is connected to the journal
+static JOURNAL: AtomicBool = AtomicBool::new(false);
+
+/// Send a state change such as `READY=1` to the service manager, if there is one
+pub fn notify(state: &str) {
+  let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+    return;
+  };
+  let Ok(socket) = UnixDatagram::unbound() else {
+    return;
+  };
+  // A leading @ names a socket in the abstract namespace
+  let result = match path.as_bytes().strip_prefix(b"@") {
+    Some(name) => SocketAddr::from_abstract_name(name)
+      .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr)),
+    None => socket.send_to(state.as_bytes(), &path),
+  };
+  if let Err(e) = result {
+    eprintln!("Could not notify systemd: {}", e);
+  }
+}
+
+/// Whether the service manager expects `WATCHDOG=1` from this process
+fn watchdog_enabled() -> bool {
+  if std::env::var_os("WATCHDOG_USEC").is_none() {
+    return false;
+  }
+  match std::env::var("WATCHDOG_PID").ok().map(|pid| pid.parse::<u32>()) {
+    Some(Ok(pid)) => pid == std::process::id(),
+    _ => true,
+  }
+}
+
+/// Feed the watchdog, called by the subscription loop each time hid-io-core answered
+///
+/// A stalled loop or a connection that cannot be restored then lets it expire.
+pub fn watchdog() {
+  if watchdog_enabled() {
+    notify("WATCHDOG=1");
+  }
+}
+
+/// Log keyboard events as structured journal entries from now on, if stdout goes to the journal
+///
+/// Events are then no longer printed on stdout, which would duplicate them in the journal.
+pub fn enable_journal() {
+  if std::env::var_os("JOURNAL_STREAM").is_some() {
+    JOURNAL.store(true, Ordering::Relaxed);
+  }
+}
+
+pub fn journal_enabled() -> bool { JOURNAL.load(Ordering::Relaxed) }
+
+/// Send a journal entry with extra `HIDIOKB_*` fields over the native protocol
+fn journal(priority: u8, message: &str, fields: &[(&str, String)]) {
+  // Values must not contain newlines in the simple form of the protocol
+  let field = |key: &str, value: &str| format!("{}={}\n", key, value.replace('\n', " "));
+  let mut entry = field("MESSAGE", message);
+  entry.push_str(&field("PRIORITY", &priority.to_string()));
+  entry.push_str(&field("SYSLOG_IDENTIFIER", "hidiokb"));
+  for (key, value) in fields {
+    entry.push_str(&field(&format!("HIDIOKB_{}", key), value));
+  }
+  let sent = UnixDatagram::unbound().and_then(|s| s.send_to(entry.as_bytes(), JOURNAL_SOCKET));
+  if sent.is_err() {
+    // Fall back to the stream journald already reads
+    println!("{}", message);
+  }
+}
+
+/// Record a keyboard event in the journal, when enabled
+pub fn log_event(serial: &str, event: &Event) {
+  if !journal_enabled() {
+    return;
+  }
+  let serial = ("SERIAL", serial.to_string());
+  let (name, message, mut fields) = match event {
+    Event::LayerChanged(layer) => {
+      ("layer-changed", format!("Layer {}", layer), vec![("LAYER", layer.to_string())])
+    }
+    Event::Volume(cmd, vol, app) => (
+      "volume",
+      format!("Volume {} {}", volume_cmd_name(*cmd), vol),
+      vec![
+        ("VOLUME_CMD", volume_cmd_name(*cmd).to_string()),
+        ("VOLUME", vol.to_string()),
+        ("APP", app.clone().unwrap_or_default()),
+      ],
+    ),
+    Event::HostMacro(index) => {
+      ("host-macro", format!("HostMacro {}", index), vec![("INDEX", index.to_string())])
+    }
+    Event::KllTrigger(index) => {
+      ("kll-trigger", format!("KllTrigger {}", index), vec![("INDEX", index.to_string())])
+    }
+    Event::CliOutput(_) => return,
+    Event::Connected => ("connected", "Keyboard connected".to_string(), Vec::new()),
+    Event::Disconnected => ("disconnected", "Keyboard disconnected".to_string(), Vec::new()),
+  };
+  fields.push(("EVENT", name.to_string()));
+  fields.push(serial.clone());
+  journal(6, &format!("[{}] {}", serial.1, message), &fields);
+}
+
+/// Record a hid-io-core connection state change in the journal, when enabled
+pub fn log_connection(message: &str, error: bool) -> bool {
+  if !journal_enabled() {
+    return false;
+  }
+  journal(if error { 3 } else { 6 }, message, &[("EVENT", "connection".to_string())]);
+  true
+}
+
+/// Write a systemd user unit running `hidiokb daemon` with `args`
+///
+/// The unit has no watchdog when `watchdog_secs` is 0. Returns the path of the unit file.
+pub fn install_service(
+  name: &str,
+  args: &[String],
+  watchdog_secs: u64,
+  force: bool,
+) -> std::io::Result<PathBuf> {
+  let exe = std::env::current_exe()?;
+  let base = crate::util::config_home().ok_or_else(|| {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "No config directory, HOME is not set")
+  })?;
+  let dir = base.join("systemd/user");
+  let path = dir.join(format!("{}.service", name));
+  if path.exists() && !force {
+    return Err(std::io::Error::new(
+      std::io::ErrorKind::AlreadyExists,
+      format!("{} already exists, pass --force to replace it", path.display()),
+    ));
+  }
+
+  let exec: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+    .chain(std::iter::once("daemon".to_string()))
+    .chain(args.iter().cloned())
+    .map(|arg| {
+      let quoted = shlex::try_quote(&arg).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {:?}", e, arg))
+      })?;
+      Ok(quoted.into_owned())
+    })
+    .collect::<std::io::Result<_>>()?;
+  let watchdog = match watchdog_secs {
+    0 => String::new(),
+    secs => format!("WatchdogSec={}\n", secs),
+  };
+  let unit = format!(
+    "[Unit]\n\
+     Description=HID-IO keyboard daemon\n\
+     After=graphical-session.target\n\
+     \n\
+     [Service]\n\
+     Type=notify\n\
+     NotifyAccess=main\n\
+     ExecStart={}\n\
+     Restart=on-failure\n\
+     RestartSec=2\n\
+     {}\
+     \n\
+     [Install]\n\
+     WantedBy=default.target\n",
+    exec.join(" "),
+    watchdog
+  );
+  std::fs::create_dir_all(&dir)?;
+  std::fs::write(&path, unit)?;
+  Ok(path)
+}
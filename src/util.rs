@@ -16,3 +16,52 @@ pub fn log_cmd(cmd: &Output) {
     }
   }
 }
+
+/// Per-user state directory ($XDG_STATE_HOME/hidiokb), used for history and remembered choices
+pub fn state_dir() -> Option<std::path::PathBuf> {
+  let base = match std::env::var_os("XDG_STATE_HOME") {
+    Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+    _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+  };
+  Some(base.join("hidiokb"))
+}
+
+/// Per-user runtime directory ($XDG_RUNTIME_DIR/hidiokb), falling back to the state directory
+pub fn runtime_dir() -> Option<std::path::PathBuf> {
+  match std::env::var_os("XDG_RUNTIME_DIR") {
+    Some(dir) if !dir.is_empty() => Some(std::path::PathBuf::from(dir).join("hidiokb")),
+    _ => state_dir(),
+  }
+}
+
+/// Base of the per-user configuration ($XDG_CONFIG_HOME, or ~/.config)
+pub fn config_home() -> Option<std::path::PathBuf> {
+  match std::env::var_os("XDG_CONFIG_HOME") {
+    Some(dir) if !dir.is_empty() => Some(std::path::PathBuf::from(dir)),
+    _ => Some(std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config")),
+  }
+}
+
+/// Per-user configuration directory ($XDG_CONFIG_HOME/hidiokb)
+pub fn config_dir() -> Option<std::path::PathBuf> { Some(config_home()?.join("hidiokb")) }
+
+/// How long the oldest process named `name` has been running, read from /proc
+pub fn process_uptime(name: &str) -> Option<std::time::Duration> {
+  // Start times in /proc/<pid>/stat are in clock ticks, which are 100Hz on every Linux target
+  const CLK_TCK: f64 = 100.0;
+  let uptime: f64 =
+    std::fs::read_to_string("/proc/uptime").ok()?.split(' ').next()?.parse().ok()?;
+  let start = std::fs::read_dir("/proc")
+    .ok()?
+    .filter_map(|entry| {
+      let path = entry.ok()?.path();
+      if std::fs::read_to_string(path.join("comm")).ok()?.trim() != name {
+        return None;
+      }
+      // The command name may contain spaces, so count fields after its closing parenthesis
+      let stat = std::fs::read_to_string(path.join("stat")).ok()?;
+      stat.rsplit_once(')')?.1.split_whitespace().nth(19)?.parse::<u64>().ok()
+    })
+    .min()?;
+  Some(std::time::Duration::from_secs_f64((uptime - start as f64 / CLK_TCK).max(0.0)))
+}
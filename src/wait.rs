@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use hid_io_core::hidio_capnp;
+
+use crate::config::Config;
+use crate::device::Selector;
+use crate::event::Condition;
+use crate::session::Session;
+
+/// Block until a matching keyboard raises an event satisfying `condition`
+///
+/// The node list is polled so keyboards plugged in while waiting are picked up; with no
+/// selectors given every keyboard is watched. Returns the serial of the keyboard that matched.
+pub async fn run(
+  hidio_auth: &hidio_capnp::hidio::Client,
+  selectors: &[Selector],
+  condition: &Condition,
+  timeout: Duration,
+) -> Result<String, String> {
+  let all = selectors.is_empty();
+  let mut session = Session::new(Config::default(), false, None);
+  // Only the serial of the matching keyboard goes to stdout
+  session.quiet();
+  let mut events = session.listen();
+
+  let wait = crate::event::wait_for(&mut events, condition, timeout);
+  tokio::pin!(wait);
+  loop {
+    let nodes_resp = hidio_auth.nodes_request().send().promise.await.map_err(|e| e.to_string())?;
+    let nodes = nodes_resp.get().and_then(|r| r.get_nodes()).map_err(|e| e.to_string())?;
+    session.sync(nodes, crate::device::find_kbs(nodes, selectors, all)).await;
+
+    tokio::select! {
+      result = &mut wait => return result,
+      _ = tokio::time::sleep(Duration::from_millis(1000)) => {}
+    }
+  }
+}